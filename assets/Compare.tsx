@@ -0,0 +1,119 @@
+import {Button, Heading, HeadingLevel} from "@ariakit/react";
+import {FormEvent, useState} from "react";
+
+type ComparisonProfile = {
+    name: string,
+    prompt?: string,
+    model?: string,
+};
+
+type CompletionUsage = {
+    prompt_tokens: number,
+    completion_tokens: number,
+    total_tokens: number,
+};
+
+type ComparisonResult = {
+    profile: ComparisonProfile,
+    text?: string,
+    error?: string,
+    usage?: CompletionUsage,
+    latency_ms: number,
+};
+
+const defaultProfiles: ComparisonProfile[] = [
+    {name: "gpt-4o-mini", model: "gpt-4o-mini"},
+    {name: "gpt-4o", model: "gpt-4o"},
+];
+
+function ProfileEditor({profile, onChange, onRemove}: {
+    profile: ComparisonProfile,
+    onChange: (profile: ComparisonProfile) => void,
+    onRemove: () => void,
+}) {
+    return (
+        <fieldset>
+            <input value={profile.name} placeholder="Name" onChange={(e) => onChange({...profile, name: e.target.value})}/>
+            <input value={profile.model ?? ""} placeholder="Default model"
+                   onChange={(e) => onChange({...profile, model: e.target.value || undefined})}/>
+            <textarea value={profile.prompt ?? ""} placeholder="Default prompt"
+                      onChange={(e) => onChange({...profile, prompt: e.target.value || undefined})}/>
+            <Button className="button secondary" onClick={onRemove}>Remove</Button>
+        </fieldset>
+    );
+}
+
+// Describes this request's screenshot with several prompt/model profiles and shows the results side by side
+export default function Compare({id}: { id: number }) {
+    const [profiles, setProfiles] = useState(defaultProfiles);
+    const [results, setResults] = useState<ComparisonResult[] | undefined>(undefined);
+    const [error, setError] = useState<string | undefined>(undefined);
+    const [pending, setPending] = useState(false);
+
+    async function compare(e: FormEvent) {
+        e.preventDefault();
+        setPending(true);
+        setError(undefined);
+
+        try {
+            const res = await fetch(`/api/request/${id}/compare`, {
+                method: "POST",
+                headers: {"Content-Type": "application/json"},
+                body: JSON.stringify({profiles}),
+            });
+            const json = await res.json();
+            if (res.ok) {
+                setResults(json);
+            } else {
+                setError(json.error);
+            }
+        } finally {
+            setPending(false);
+        }
+    }
+
+    const editors = profiles.map((profile, i) => (
+        <ProfileEditor
+            key={i}
+            profile={profile}
+            onChange={(changed) => setProfiles(profiles.map((p, j) => i === j ? changed : p))}
+            onRemove={() => setProfiles(profiles.filter((_, j) => i !== j))}
+        />
+    ));
+
+    return (
+        <HeadingLevel>
+            <Heading>Compare</Heading>
+            <form onSubmit={compare}>
+                {editors}
+                <Button className="button secondary" onClick={() => setProfiles([...profiles, {name: `Profile ${profiles.length + 1}`}])}>
+                    Add Profile
+                </Button>
+                <Button type="submit" className="button" disabled={pending || profiles.length === 0}>Compare</Button>
+            </form>
+            {error && <p className="error">{error}</p>}
+            {results && (
+                <table className="comparison">
+                    <thead>
+                    <tr>
+                        <th>Profile</th>
+                        <th>Output</th>
+                        <th>Tokens (prompt / completion)</th>
+                        <th>Latency</th>
+                    </tr>
+                    </thead>
+                    <tbody>
+                    {results.map((result, i) => (
+                        <tr key={i}>
+                            <th>{result.profile.name}</th>
+                            <td className={result.error ? "error" : undefined}>{result.text ?? result.error}</td>
+                            <td>{result.usage ? `${result.usage.prompt_tokens} / ${result.usage.completion_tokens}` : "—"}</td>
+                            <td>{result.latency_ms} ms</td>
+                        </tr>
+                    ))}
+                    </tbody>
+                </table>
+            )}
+        </HeadingLevel>
+    );
+}
@@ -0,0 +1,66 @@
+import {Button, Heading, HeadingLevel} from "@ariakit/react";
+import {FormEvent, useEffect, useState} from "react";
+import {useSWRConfig} from "swr";
+
+type Rating = "good" | "bad";
+
+export type FeedbackRecord = {
+    rating?: Rating,
+    correction?: string,
+};
+
+// Lets a person rate a call's output and say what it should have been instead
+export default function Feedback({id, feedback}: { id: number, feedback?: FeedbackRecord }) {
+    const {mutate} = useSWRConfig();
+    const [rating, setRating] = useState<Rating | undefined>(feedback?.rating);
+    const [correction, setCorrection] = useState(feedback?.correction ?? "");
+    const [pending, setPending] = useState(false);
+
+    useEffect(() => {
+        setRating(feedback?.rating);
+        setCorrection(feedback?.correction ?? "");
+    }, [feedback?.rating, feedback?.correction]);
+
+    async function send(method: "PUT" | "DELETE", body?: FeedbackRecord) {
+        setPending(true);
+        try {
+            await fetch(`/api/request/${id}/feedback`, {
+                method,
+                headers: body ? {"Content-Type": "application/json"} : undefined,
+                body: body ? JSON.stringify(body) : undefined,
+            });
+            await mutate(`/api/request/${id}`);
+        } finally {
+            setPending(false);
+        }
+    }
+
+    async function save(e: FormEvent) {
+        e.preventDefault();
+        await send("PUT", {rating, correction: correction || undefined});
+    }
+
+    return (
+        <HeadingLevel>
+            <Heading>Feedback</Heading>
+            <form onSubmit={save}>
+                <fieldset>
+                    <label>
+                        <input type="radio" name="rating" checked={rating === "good"} onChange={() => setRating("good")}/>
+                        Good
+                    </label>
+                    <label>
+                        <input type="radio" name="rating" checked={rating === "bad"} onChange={() => setRating("bad")}/>
+                        Bad
+                    </label>
+                </fieldset>
+                <label>
+                    Correction
+                    <textarea value={correction} placeholder="What the output should have been" onChange={(e) => setCorrection(e.target.value)}/>
+                </label>
+                <Button type="submit" className="button" disabled={pending}>Save</Button>
+                <Button type="button" className="button secondary" disabled={pending || !feedback} onClick={() => send("DELETE")}>Clear</Button>
+            </form>
+        </HeadingLevel>
+    );
+}
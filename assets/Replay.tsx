@@ -0,0 +1,88 @@
+import {Button, Heading, HeadingLevel} from "@ariakit/react";
+import {FormEvent, useState} from "react";
+import {useSWRConfig} from "swr";
+import {isRequestList} from "./ServiceCall";
+
+type Voice = "alloy" | "echo" | "fable" | "onyx" | "nova" | "shimmer";
+type QueryOverrides = {
+    prompt?: string,
+    model?: string,
+    voice?: Voice,
+};
+
+type ReplayResult = {
+    id: number,
+    response: { text?: string, error?: string },
+};
+
+export type ReplayRecord = {
+    of: number,
+    overrides: QueryOverrides,
+};
+
+// Runs a recorded request through the AI service again, optionally with a different prompt, model or voice
+export default function Replay({id}: { id: number }) {
+    const {mutate} = useSWRConfig();
+    const [prompt, setPrompt] = useState("");
+    const [model, setModel] = useState("");
+    const [voice, setVoice] = useState("");
+    const [result, setResult] = useState<ReplayResult | undefined>(undefined);
+    const [pending, setPending] = useState(false);
+
+    async function replay(e: FormEvent) {
+        e.preventDefault();
+        setPending(true);
+
+        const overrides: QueryOverrides = {
+            prompt: prompt || undefined,
+            model: model || undefined,
+            voice: (voice || undefined) as Voice | undefined,
+        };
+
+        try {
+            const res = await fetch(`/api/request/${id}/replay`, {
+                method: "POST",
+                headers: {"Content-Type": "application/json"},
+                body: JSON.stringify(overrides),
+            });
+            setResult(await res.json());
+            await mutate(isRequestList);
+        } finally {
+            setPending(false);
+        }
+    }
+
+    return (
+        <HeadingLevel>
+            <Heading>Replay</Heading>
+            <form onSubmit={replay}>
+                <label>
+                    Prompt
+                    <textarea value={prompt} placeholder="Default prompt" onChange={(e) => setPrompt(e.target.value)}/>
+                </label>
+                <label>
+                    Model
+                    <input value={model} placeholder="Default model" onChange={(e) => setModel(e.target.value)}/>
+                </label>
+                <label>
+                    Voice
+                    <select value={voice} onChange={(e) => setVoice(e.target.value)}>
+                        <option value="">Default voice</option>
+                        <option value="alloy">Alloy</option>
+                        <option value="echo">Echo</option>
+                        <option value="fable">Fable</option>
+                        <option value="onyx">Onyx</option>
+                        <option value="nova">Nova</option>
+                        <option value="shimmer">Shimmer</option>
+                    </select>
+                </label>
+                <Button type="submit" className="button" disabled={pending}>Replay</Button>
+            </form>
+            {result && (
+                <p>
+                    Recorded as request #{result.id}: {result.response.text ?? result.response.error ?? "(non-text response)"}
+                </p>
+            )}
+        </HeadingLevel>
+    );
+}
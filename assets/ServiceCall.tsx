@@ -3,13 +3,33 @@ import {Button, Dialog, DialogDismiss, DialogHeading, HeadingLevel, Heading} fro
 import {ReactElement, useState} from "react";
 import ChatCompletionCreateParams from "openai";
 import ChatCompletion from "openai";
+import Replay, {ReplayRecord} from "./Replay";
+import Compare from "./Compare";
+import Feedback, {FeedbackRecord} from "./Feedback";
 
-const fetcher = (url: string) => fetch(url).then((res) => res.json());
+class FetchError extends Error {
+    constructor(public status: number, message: string) {
+        super(message);
+    }
+}
+
+export const fetcher = async (url: string) => {
+    const res = await fetch(url);
+    if (!res.ok) {
+        throw new FetchError(res.status, `${res.status} ${res.statusText}`);
+    }
+
+    return res.json();
+};
+
+// Matches every page and filter of the list of calls, for revalidating them all at once
+export const isRequestList = (key: any) => typeof key === "string" && key.startsWith("/api/request?");
 type Headers = { [key: string]: string };
 type ServiceRequest = {
     headers: Headers,
     params: string,
     body: RequestBody,
+    image?: string,
 }
 
 type ServiceResponse = {
@@ -58,14 +78,36 @@ type ResponseBody = {
     auto?: "auto" | "continue",
 }
 
+type ServiceFailure = {
+    kind: string,
+    status: number,
+    message: string,
+    detail: string,
+}
+
+type Artifacts = {
+    text?: string,
+    sound?: string,
+    image?: string,
+};
+
 type ServiceCallArgs = { id: number };
 type ServiceCallState = { data?: ServiceCallRecord, error: any, isLoading: boolean };
-type ServiceCallRecord = {
+export type ServiceCallRecord = {
     id: number,
+    timestamp: number,
+    session: number,
     client_request: ServiceRequest,
+    replay?: ReplayRecord,
+    openai_messages?: Array<object>,
     openai_request?: {CreateChatCompletionRequest: ChatCompletionCreateParams}, // OpenAiRequest,
     openai_response?: {CreateChatCompletionResponse: ChatCompletion}, //OpenAiResponse
     client_response?: ServiceResponse, // ServiceResponse,
+    error?: ServiceFailure,
+    response_text?: string,
+    artifacts?: Artifacts,
+    corrected_from?: number,
+    feedback?: FeedbackRecord,
 };
 
 function ClientRequest({request}: { request: ServiceRequest }) {
@@ -141,6 +183,48 @@ function ClientResponse({response}: { response: ServiceResponse | undefined }) {
     );
 }
 
+// What the player actually got: the text, the spoken audio, and the overlay drawn over their screenshot
+function Output({screenshot, text, artifacts}: { screenshot: string, text?: string, artifacts?: Artifacts }) {
+    if (!text && !artifacts?.sound && !artifacts?.image) {
+        return <></>;
+    }
+
+    return (
+        <HeadingLevel>
+            <Heading>Output</Heading>
+            {text && <blockquote>{text}</blockquote>}
+            {artifacts?.sound && <audio controls src={artifacts.sound}/>}
+            {artifacts?.image && (
+                <div className="overlay">
+                    <img src={screenshot} alt="Screenshot"/>
+                    <img src={artifacts.image} alt="Overlay"/>
+                </div>
+            )}
+        </HeadingLevel>
+    );
+}
+
+function Failure({failure}: { failure?: ServiceFailure }) {
+    if (!failure) {
+        return <></>;
+    }
+
+    return (
+        <HeadingLevel>
+            <Heading>Error</Heading>
+            <dl className="error">
+                <dt>Kind</dt>
+                <dd><code>{failure.kind}</code></dd>
+                <dt>Status</dt>
+                <dd><code>{failure.status}</code></dd>
+                <dt>Shown to Player</dt>
+                <dd>{failure.message}</dd>
+                <dt>Details</dt>
+                <dd><pre>{failure.detail}</pre></dd>
+            </dl>
+        </HeadingLevel>
+    );
+}
 
 export default function ServiceCall({id}: ServiceCallArgs) {
     const [open, setOpen] = useState(false);
@@ -151,14 +235,18 @@ export default function ServiceCall({id}: ServiceCallArgs) {
         return <div>Loading...</div>;
     } // TODO: Make a nice-looking loading message
 
+    if (error instanceof FetchError && error.status === 404) {
+        return <></>;
+    } // The call was evicted from the server's history
+
     if (error) {
-        return <div>Error: {error}</div>;
+        return <div>Error: {`${error}`}</div>;
     } // TODO: Make a nice-looking error message
 
 
     return (<>
-        <Button className="button" onClick={() => setOpen(true)}>
-            <img src={imageUrl} alt={`Screenshot #${id}`} height="48"/>
+        <Button className={data?.error ? "button error" : "button"} onClick={() => setOpen(true)}>
+            <img src={`/api/request/${id}/thumbnail`} alt={`Screenshot #${id}`} height="48" loading="lazy"/>
         </Button>
         <Dialog
             open={open}
@@ -172,12 +260,23 @@ export default function ServiceCall({id}: ServiceCallArgs) {
             )}
         >
             <DialogHeading className="heading">Request #{id}</DialogHeading>
+            <p>
+                Received <time dateTime={new Date(data!.timestamp).toISOString()}>{new Date(data!.timestamp).toLocaleString()}</time>
+                {" "}during the session started {new Date(data!.session).toLocaleString()}
+                {data!.replay && <>{" "}as a replay of request #{data!.replay.of}</>}
+                {data!.corrected_from !== undefined && data!.corrected_from !== null && <>, answered with the correction of request #{data!.corrected_from}</>}
+            </p>
             <img src={imageUrl} alt={`Screenshot #${id}`}/>
             <div>
+                <Output screenshot={imageUrl} text={data!.response_text} artifacts={data!.artifacts}/>
                 <ClientRequest request={data!.client_request}/>
                 <OpenAiRequest request={data?.openai_request?.CreateChatCompletionRequest}/>
                 <OpenAiResponse response={data!.openai_response?.CreateChatCompletionResponse}/>
                 <ClientResponse response={data!.client_response}/>
+                <Failure failure={data!.error}/>
+                <Feedback id={id} feedback={data!.feedback}/>
+                <Replay id={id}/>
+                <Compare id={id}/>
             </div>
 
 
@@ -0,0 +1,65 @@
+import {Button, Heading, HeadingLevel} from "@ariakit/react";
+import useSWR from "swr";
+import {useState} from "react";
+import {fetcher} from "./ServiceCall";
+
+type SessionSummary = {
+    client: string,
+    label: string,
+    narration?: string,
+    changes: Array<string>,
+    memory?: string,
+    frames: number,
+    idle: number,
+};
+
+type SessionsState = { data: Array<SessionSummary> | undefined, error: any, mutate: () => Promise<any> };
+
+// What groan remembers about each client's game: its last narration and its story memory
+export default function Sessions() {
+    const {data, error, mutate}: SessionsState = useSWR("/api/session", fetcher, {refreshInterval: 5000});
+    const [pending, setPending] = useState(false);
+
+    async function resetMemory(session: SessionSummary) {
+        setPending(true);
+        try {
+            const query = new URLSearchParams({client: session.client, label: session.label});
+            await fetch(`/api/session/memory?${query}`, {method: "DELETE"});
+            await mutate();
+        } finally {
+            setPending(false);
+        }
+    }
+
+    if (error) {
+        return <div>Error: {`${error}`}</div>;
+    }
+
+    if (!data || data.length === 0) {
+        return <></>;
+    }
+
+    return (
+        <HeadingLevel>
+            <Heading>Sessions</Heading>
+            {data.map((session) => (
+                <section key={`${session.client} ${session.label}`} className="session">
+                    <p>
+                        <code>{session.client}</code> playing <strong>{session.label || "(no label)"}</strong>,
+                        last seen {session.idle} seconds ago, with {session.frames} earlier screenshots kept
+                    </p>
+                    {session.narration && <blockquote>{session.narration}</blockquote>}
+                    {session.changes.length > 0 && (
+                        <ol>
+                            {session.changes.map((change, index) => <li key={index}>{change}</li>)}
+                        </ol>
+                    )}
+                    <pre>{session.memory ?? "No story memory yet"}</pre>
+                    <Button className="button secondary" disabled={pending || !session.memory} onClick={() => resetMemory(session)}>
+                        Reset memory
+                    </Button>
+                </section>
+            ))}
+        </HeadingLevel>
+    );
+}
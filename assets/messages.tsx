@@ -1,14 +1,114 @@
 import {Collection, CollectionItem, Button, Dialog, DialogDismiss, DialogHeading} from "@ariakit/react";
-import useSWR from 'swr';
-import ServiceCall from "./ServiceCall";
+import useSWR, {useSWRConfig} from 'swr';
+import {useEffect, useState} from "react";
+import ServiceCall, {fetcher, isRequestList, ServiceCallRecord} from "./ServiceCall";
+import Sessions from "./Sessions";
 
-const fetcher = (url: string) => fetch(url).then((res) => res.json());
+type RequestIds = { ids: Array<number>; total: number; };
+type RequestFilters = {
+    order: "newest" | "oldest",
+    output: string,
+    label: string,
+    status: string,
+    model: string,
+    q: string,
+};
 
-type RequestIds = { ids: Array<number>; };
+const PAGE_SIZE = 50;
 type ServiceCallsState = { data: RequestIds | undefined, error: any, isLoading: boolean };
 
+// What the server pushes when a message changes a call it already announced
+type CallUpdate = Partial<ServiceCallRecord> & { openai_message?: object };
+
+// Keeps SWR's cache up-to-date with the calls that the server pushes as they happen
+function useLiveUpdates() {
+    const {cache, mutate} = useSWRConfig();
+
+    useEffect(() => {
+        const events = new EventSource("/api/events");
+        const update = (e: MessageEvent) => {
+            const key = `/api/request/${e.lastEventId}`;
+            const call: ServiceCallRecord | undefined = cache.get(key)?.data;
+            if (!call) {
+                mutate(key); // Not loaded yet, so there's nothing to merge the change into
+                return;
+            }
+
+            const {openai_message, ...changes}: CallUpdate = JSON.parse(e.data);
+            const openai_messages = openai_message ? [...(call.openai_messages ?? []), openai_message] : call.openai_messages;
+            mutate(key, {...call, ...changes, openai_messages}, {revalidate: false});
+        };
+
+        events.addEventListener("client_request", (e) => {
+            mutate(`/api/request/${e.lastEventId}`, JSON.parse(e.data), {revalidate: false});
+            mutate(isRequestList);
+        });
+        events.addEventListener("replay", update);
+        events.addEventListener("correction_used", update);
+        events.addEventListener("openai_message", update);
+        events.addEventListener("client_response", update);
+        events.addEventListener("feedback", update);
+        events.addEventListener("response_text", update);
+        events.addEventListener("response_image", update);
+        events.addEventListener("error", (e) => {
+            if (e instanceof MessageEvent) {
+                update(e);
+            } // Otherwise it's a connection error, which EventSource recovers from by itself
+        });
+        events.addEventListener("lagged", () => mutate(() => true));
+
+        return () => events.close();
+    }, [cache, mutate]);
+}
+
+// Narrows down the list of calls; empty fields don't filter anything
+function Filters({filters, onChange}: { filters: RequestFilters, onChange: (filters: RequestFilters) => void }) {
+    const set = (name: keyof RequestFilters) => (e: { target: { value: string } }) => {
+        onChange({...filters, [name]: e.target.value});
+    };
+
+    return (
+        <form className="filters" onSubmit={(e) => e.preventDefault()}>
+            <select value={filters.order} onChange={set("order")}>
+                <option value="newest">Newest first</option>
+                <option value="oldest">Oldest first</option>
+            </select>
+            <select value={filters.output} onChange={set("output")}>
+                <option value="">Any output</option>
+                <option value="text">Text</option>
+                <option value="sound">Sound</option>
+                <option value="image">Image</option>
+            </select>
+            <select value={filters.status} onChange={set("status")}>
+                <option value="">Any status</option>
+                <option value="success">Succeeded</option>
+                <option value="error">Failed</option>
+            </select>
+            <input value={filters.label} placeholder="Label" onChange={set("label")}/>
+            <input value={filters.model} placeholder="Model" onChange={set("model")}/>
+            <input type="search" value={filters.q} placeholder="Search outputs" onChange={set("q")}/>
+        </form>
+    );
+}
+
 export function ServiceCalls() {
-    const {data, error, isLoading}: ServiceCallsState = useSWR('/api/request', fetcher);
+    const [filters, setFilters] = useState<RequestFilters>({order: "newest", output: "", label: "", status: "", model: "", q: ""});
+    const [page, setPage] = useState(0);
+    const query = new URLSearchParams({offset: `${page * PAGE_SIZE}`, limit: `${PAGE_SIZE}`});
+    for (const [name, value] of Object.entries(filters)) {
+        if (value) {
+            query.set(name, value);
+        }
+    }
+
+    const {data, error, isLoading}: ServiceCallsState = useSWR(`/api/request?${query}`, fetcher, {keepPreviousData: true});
+    useLiveUpdates();
+
+    const changeFilters = (filters: RequestFilters) => {
+        setFilters(filters);
+        setPage(0);
+    };
+    const pages = Math.max(1, Math.ceil((data?.total ?? 0) / PAGE_SIZE));
 
     if (isLoading) {
         return <div>Loading...</div>;
@@ -26,9 +126,22 @@ export function ServiceCalls() {
         </CollectionItem>
     ));
 
-    return (
+    return (<>
+        <nav>
+            Export as{" "}
+            <a href="/api/export?format=jsonl" download>JSONL</a>,{" "}
+            <a href="/api/export?format=zip" download>zip</a>, or{" "}
+            <a href="/api/export?format=openai" download>OpenAI fine-tuning JSONL</a>
+        </nav>
+        <Sessions/>
+        <Filters filters={filters} onChange={changeFilters}/>
         <Collection>
             {calls}
         </Collection>
-    )
+        <nav className="pages">
+            <Button className="button secondary" disabled={page === 0} onClick={() => setPage(page - 1)}>Previous</Button>
+            Page {page + 1} of {pages} ({data?.total ?? 0} calls)
+            <Button className="button secondary" disabled={page + 1 >= pages} onClick={() => setPage(page + 1)}>Next</Button>
+        </nav>
+    </>)
 }
\ No newline at end of file
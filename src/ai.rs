@@ -1,43 +1,141 @@
 use std::collections::HashMap;
-use std::error::Error;
 use std::io::BufWriter;
+use std::net::SocketAddr;
+use crate::auth::{Forbidden, ServiceAccess};
+use crate::error::{ServiceError, ServiceFailure};
+use crate::feedback::{Correction, CorrectionOptions, Corrections};
+use crate::wav;
+use crate::wav::AudioOptions;
+use crate::session::{self, Context, Frame, Recall, SessionKey, Sessions};
+use crate::stats::PipelineStats;
+use crate::store;
+use crate::text::{SentenceSplitter, TextOptions};
+use crate::tts::{SpeechBackend, SpeechOptions, VoiceSettings};
 use crate::types::{
-    ImageOutputFormat, InvalidRequestBody, RequestBody, RequestParams, ResponseBody,
+    InvalidInput, RequestBody, RequestParams, ResponseBody,
 };
 use async_openai::config::OpenAIConfig;
-use async_openai::types::{ChatCompletionRequestMessage, ChatCompletionRequestMessageContentPart, ChatCompletionRequestMessageContentPartImageArgs, ChatCompletionRequestSystemMessageArgs, ChatCompletionRequestUserMessageArgs, ChatCompletionResponseMessage, CreateChatCompletionRequest, CreateChatCompletionRequestArgs, CreateChatCompletionResponse, CreateSpeechRequest, CreateSpeechRequestArgs, CreateSpeechResponse};
+use async_openai::types::{ChatCompletionRequestAssistantMessageArgs, ChatCompletionRequestMessage, CompletionUsage, ChatCompletionRequestMessageContentPart, ChatCompletionRequestMessageContentPartImageArgs, ChatCompletionRequestMessageContentPartTextArgs, ChatCompletionRequestSystemMessageArgs, ChatCompletionRequestUserMessageArgs, ChatCompletionResponseMessage, CreateChatCompletionRequest, CreateChatCompletionRequestArgs, CreateChatCompletionResponse, CreateChatCompletionStreamResponse, CreateSpeechRequest, CreateSpeechRequestArgs, CreateSpeechResponse};
 use async_openai::Client;
-use bytes::{buf, BufMut, Bytes, BytesMut};
+use bytes::{buf, BufMut, Bytes};
 use serde::{Deserialize, Serialize};
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
 use std::sync::atomic::AtomicU64;
+use std::time::Instant;
 use async_openai::error::OpenAIError;
 use async_openai::types::SpeechModel::Tts1;
 use async_openai::types::SpeechResponseFormat::Wav;
+use async_openai::types::Voice;
 use async_openai::types::Voice::Fable;
 use base64::Engine;
 use base64::prelude::BASE64_STANDARD;
 use image::codecs::png::PngEncoder;
 use image::{ImageEncoder, ImageFormat, RgbImage, RgbaImage};
 use serde_json::Value;
+use tokio::sync::mpsc::error::TrySendError;
 use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+use tokio_stream::StreamExt;
 use warp::Filter;
+use warp::http::StatusCode;
 use warp::hyper::HeaderMap;
 
+const DEFAULT_PROMPT: &str = "You are a narration service helping a visually impaired player \
+    understand the scene for the game they're playing. \
+    Describe the contents of the screenshots you will be given. \
+    Limit your response to one sentence. \
+    Do not use headings or explicit section makers. \
+    Do not speculate about the image's contents. \
+    Use video game terminology if appropriate.";
+
+const DEFAULT_MODEL: &str = "gpt-4o-mini";
+
+/// The most tokens the model may answer with; narrations and memories are well under this.
+const MAX_TOKENS: u32 = 300;
+
+const MEMORY_PROMPT: &str = "You keep a running memory of a video game session for a narration service. \
+    Given the memory so far and the latest narration, answer with the updated memory and nothing else. \
+    Keep track of the characters met (with their names and pronouns), the current location, \
+    the current objectives, and important events. \
+    Drop details that no longer matter, and keep the memory under 150 words.";
+
 pub(crate) type MessageSender = Sender<(u64, ServiceMessage)>;
 pub(crate) type MessageReceiver = Receiver<(u64, ServiceMessage)>;
 
+/// Text-to-speech requests in flight, each of which yields its position in the narration and a WAV file.
+type SpeechTasks = JoinSet<Result<(usize, Bytes), ServiceError>>;
+
 pub(crate) struct AiService {
     client: Arc<Client<OpenAIConfig>>,
     sender: MessageSender,
+    stats: Arc<PipelineStats>,
     next_id: AtomicU64,
+    corrections: RwLock<Corrections>,
+    correction_options: CorrectionOptions,
+    output: OutputOptions,
+    sessions: Sessions,
+    /// Limits how many text-to-speech requests run at once, across every client.
+    speech_permits: Semaphore,
+}
+
+/// How responses are cleaned up, spoken and encoded before they go back to RetroArch.
+#[derive(Debug, Clone)]
+pub(crate) struct OutputOptions {
+    pub(crate) text: TextOptions,
+    pub(crate) speech: SpeechOptions,
+    pub(crate) audio: AudioOptions,
+}
+
+/// A request as RetroArch sent it.
+/// The AI service parses it once and shares the parsed body with the web console rather than copying it.
+#[derive(Debug)]
+pub(crate) struct ClientRequest {
+    pub(crate) headers: HeaderMap,
+    pub(crate) params: String,
+    pub(crate) body: RecordedBody,
+}
+
+#[derive(Debug)]
+pub(crate) enum RecordedBody {
+    /// The same body that the AI service is answering.
+    Parsed(Arc<RequestBody>),
+    /// The body couldn't be parsed, so it's passed on as text.
+    Malformed(String),
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize)]
 pub(crate) struct ServiceRequest {
     pub(crate) headers: HashMap<String, String>,
     pub(crate) params: String,
+    /// The body without its screenshot, which the console keeps (decoded) on its own.
     pub(crate) body: Value,
+    /// Where the console serves the screenshot from, if there was one.
+    pub(crate) image: Option<String>,
+}
+
+impl ServiceRequest {
+    pub(crate) fn image_url(id: u64) -> String {
+        format!("/api/request/{}/image", id)
+    }
+}
+
+/// How a chat request refers to screenshots.
+#[derive(Debug, Clone, Copy)]
+enum ImageUrls {
+    /// Inlined as data URLs, which is what OpenAI needs.
+    Inline,
+    /// Linked to the web console's copies, so that recording the request doesn't copy the screenshots again.
+    Console,
+}
+
+impl ImageUrls {
+    fn url(self, id: u64, image: &str) -> String {
+        match self {
+            ImageUrls::Inline => data_url(image),
+            ImageUrls::Console => ServiceRequest::image_url(id),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -45,7 +143,10 @@ pub(crate) enum OpenAiMessage {
     CreateChatCompletionRequest(CreateChatCompletionRequest),
     CreateSpeechRequest(CreateSpeechRequest),
     CreateChatCompletionResponse(CreateChatCompletionResponse),
-    CreateSpeechResponse(Bytes),
+    /// Every chunk of a streamed chat completion, once the stream has ended.
+    CreateChatCompletionStreamResponse(Vec<CreateChatCompletionStreamResponse>),
+    /// The spoken response, which the console serves (and saves) on its own, so it's serialized as its length in bytes.
+    CreateSpeechResponse(#[serde(serialize_with = "serialize_length")] Bytes),
 }
 
 #[derive(Debug, Serialize)]
@@ -54,11 +155,54 @@ pub(crate) struct ServiceResponse {
     pub(crate) body: Value,
 }
 
+/// Settings that a single call may use instead of the service's defaults, e.g. when it's replayed.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub(crate) struct QueryOverrides {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) prompt: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) model: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) voice: Option<Voice>,
+}
+
+/// A named set of overrides to try in a comparison run.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct ComparisonProfile {
+    pub(crate) name: String,
+    #[serde(flatten)]
+    pub(crate) overrides: QueryOverrides,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct ComparisonResult {
+    pub(crate) profile: ComparisonProfile,
+    pub(crate) text: Option<String>,
+    pub(crate) error: Option<String>,
+    pub(crate) usage: Option<CompletionUsage>,
+    pub(crate) latency_ms: u64,
+}
+
 #[derive(Debug)]
 pub(crate) enum ServiceMessage {
-    ClientRequest(HeaderMap, String, Bytes),
+    ClientRequest(ClientRequest),
+    /// Sent right after `ClientRequest` if the request is a replay of the call with the given ID.
+    Replay(u64, QueryOverrides),
+    /// Sent instead of asking the model if the request's screenshot was already corrected in the call with the given ID.
+    CorrectionUsed(u64),
     OpenAiMessage(OpenAiMessage),
     ClientResponse(HeaderMap, Bytes),
+    /// The text that the client was sent, or that was spoken to it.
+    ResponseText(String),
+    /// The encoded image sent back to the client, for requests that asked for one.
+    ResponseImage(Bytes),
+    Error(ServiceFailure),
+}
+
+impl From<ClientRequest> for ServiceMessage {
+    fn from(request: ClientRequest) -> Self {
+        ServiceMessage::ClientRequest(request)
+    }
 }
 
 impl From<CreateChatCompletionResponse> for ServiceMessage {
@@ -89,20 +233,59 @@ impl AiService {
     pub(crate) fn next_id(&self) -> u64 {
         self.next_id.fetch_add(1, std::sync::atomic::Ordering::SeqCst)
     }
-    pub(crate) fn service(
+
+    /// Hands a message to the web console without waiting for it.
+    /// The console is only an observer, so if it's falling behind the message is dropped rather than delaying the player;
+    /// either way, failing to reach it is logged and counted but never fails the request.
+    fn record<T>(&self, id: u64, message: T)
+    where
+        T: Into<ServiceMessage>,
+    {
+        match self.sender.try_send((id, message.into())) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                let dropped = PipelineStats::count(&self.stats.dropped_messages);
+                log::debug!(target: "groan", "The web console is falling behind; dropped a message for request {} ({} dropped so far)", id, dropped);
+            }
+            Err(TrySendError::Closed(_)) => {
+                let unsent = PipelineStats::count(&self.stats.unsent_messages);
+                log::warn!(target: "groan", "Couldn't send message for request {} to the web console ({} unsent so far): it's shut down", id, unsent);
+            }
+        }
+    }
+
+    pub(crate) fn new(
         client: Arc<Client<OpenAIConfig>>,
         sender: MessageSender,
-    ) -> impl Filter<Extract=(impl warp::Reply,), Error=warp::Rejection> + Clone {
-        let service = Arc::new(Self { client, sender, next_id: AtomicU64::new(0) });
+        stats: Arc<PipelineStats>,
+        first_id: u64,
+        correction_options: CorrectionOptions,
+        output: OutputOptions,
+        sessions: Sessions,
+    ) -> Arc<Self> {
+        Arc::new(Self {
+            client,
+            sender,
+            stats,
+            next_id: AtomicU64::new(first_id),
+            corrections: RwLock::new(Corrections::new(correction_options.limit)),
+            correction_options,
+            speech_permits: Semaphore::new(output.speech.concurrency),
+            output,
+            sessions,
+        })
+    }
+
+    pub(crate) fn service(self: Arc<Self>, access: Arc<ServiceAccess>) -> impl Filter<Extract=(impl warp::Reply,), Error=warp::Rejection> + Clone {
+        let service = self;
 
         warp::post() // Accept only POST requests...
-            // ...at the root path...
-            .and(warp::path::end())
-            // ...with query parameters that suit RequestParams...
-            .and(warp::query::<RequestParams>())
-            .and(warp::query::raw())
+            // ...from allowed clients at the root path (or at the token, if there is one)...
+            .and(access.filter())
             // ...and including the HTTP headers...
             .and(warp::header::headers_cloned())
+            // ...and the client's address, which tells one player's narration from another's...
+            .and(warp::addr::remote())
             // ...regardless of the declared content type.
             .and(warp::body::bytes())
             // ...and pass along the service object itself.
@@ -111,47 +294,145 @@ impl AiService {
             // RetroArch declares application/x-www-form-urlencoded for its AI service requests,
             // but the body is actually JSON;
             // hence we deserialize explicitly because warp doesn't know how to handle this discrepancy.
-            .and_then(|params: RequestParams, raw_params: String, headers: HeaderMap, body: Bytes, service: Arc<AiService>| async move {
+            // The query parameters are deserialized here too, so that a request with bad ones is still recorded.
+            .and_then(|raw_params: String, headers: HeaderMap, remote: Option<SocketAddr>, body: Bytes, service: Arc<AiService>| async move {
                 let request_id = service.next_id();
                 log::info!(target: "groan", "{:?}", raw_params);
 
-                if let Ok(request_body) = serde_json::from_slice::<RequestBody>(body.iter().as_slice()) {
-                    log::info!(target: "groan", "{:?}", request_body);
+                let request_body = serde_json::from_slice::<RequestBody>(body.iter().as_slice()).map(Arc::new);
+                let recorded = match &request_body {
+                    Ok(request_body) => RecordedBody::Parsed(request_body.clone()),
+                    Err(_) => RecordedBody::Malformed(String::from_utf8_lossy(&body).into_owned()),
+                };
+                service.record(request_id, ClientRequest { headers, params: raw_params.clone(), body: recorded });
 
-                    let request = ServiceMessage::ClientRequest(headers, raw_params, body);
-                    service.sender.send((request_id, request)).await.expect("TODO: panic message");
+                let input = serde_urlencoded::from_str::<RequestParams>(&raw_params)
+                    .map_err(|e| format!("malformed query parameters ({}; expected source_lang, target_lang, and output)", e))
+                    .and_then(|params| {
+                        let request_body = request_body.map_err(|e| format!("malformed request body ({})", e))?;
+                        Ok((params, request_body))
+                    });
 
-                    Ok((request_id, params, request_body, service))
-                } else {
-                    let request = ServiceMessage::ClientRequest(headers, raw_params, body);
-                    service.sender.send((request_id, request)).await.expect("TODO: panic message");
+                match input {
+                    Ok((params, request_body)) => {
+                        log::info!(target: "groan", "{:?}", request_body);
+
+                        let session = remote.map(|remote| SessionKey { client: remote.ip(), label: request_body.label.clone() });
+                        Ok((request_id, params, request_body, session, service))
+                    }
+                    Err(reason) => {
+                        // Record the failure now, since the rejection handler doesn't know which call it's for
+                        let error = ServiceError::BadInput(reason.clone());
+                        service.record(request_id, ServiceMessage::Error(error.to_failure()));
 
-                    Err(warp::reject::custom(InvalidRequestBody))
+                        Err(warp::reject::custom(InvalidInput(reason)))
+                    }
                 }
             })
             // Then we untuple the parameters and body...
             .untuple_one()
             // query_service may run on another thread, possibly with multiple instances;
             // therefore we create the client in an `Arc` and clone it for each call to this endpoint
-            .then(move |id, params, body, service| async move {
-                AiService::query_service(id, service, params, body).await.unwrap_or_else(|e| {
-                    log::error!(target: "groan", "{:?}", e);
-                    ResponseBody::error(format!("{:?}", e))
-                })
+            .then(move |id, params, body, session: Option<SessionKey>, service: Arc<AiService>| async move {
+                AiService::answer(id, service, params, body, session.as_ref(), &QueryOverrides::default()).await
             })
             // Now that we've got the response, convert it to JSON...
-            .map(|response| {
-                warp::reply::json(&response)
+            .map(|(response, status)| {
+                warp::reply::with_status(warp::reply::json(&response), status)
             })
+            // Turn malformed requests into responses that RetroArch can display...
+            .recover(AiService::handle_rejection)
             .with(warp::trace::named("groan"))
     }
 
+    /// Runs a request through `query_service`, recording any failure with the web console.
+    /// Requests without a session (such as replays) are narrated in full, and don't affect any session.
+    async fn answer(
+        id: u64,
+        service: Arc<AiService>,
+        params: RequestParams,
+        body: Arc<RequestBody>,
+        session: Option<&SessionKey>,
+        overrides: &QueryOverrides,
+    ) -> (ResponseBody, StatusCode) {
+        match AiService::query_service(id, service.clone(), params, body, session, overrides).await {
+            Ok(response) => (response, StatusCode::OK),
+            Err(e) => {
+                log::log!(target: "groan", e.log_level(), "Request {} failed: {}", id, e);
+                service.record(id, ServiceMessage::Error(e.to_failure()));
+
+                (e.to_response(), e.status())
+            }
+        }
+    }
+
+    /// Runs a previously-recorded request through the service again, as a new call.
+    /// Returns the new call's ID along with its response.
+    pub(crate) async fn replay(
+        self: Arc<Self>,
+        original: u64,
+        raw_params: String,
+        body: Value,
+        overrides: QueryOverrides,
+    ) -> (u64, ResponseBody, StatusCode) {
+        let id = self.next_id();
+        log::info!(target: "groan", "Replaying request {} as {} with {:?}", original, id, overrides);
+
+        let (recorded, request_body) = match serde_json::from_value::<RequestBody>(body.clone()) {
+            Ok(request_body) => {
+                let request_body = Arc::new(request_body);
+                (RecordedBody::Parsed(request_body.clone()), Ok(request_body))
+            }
+            Err(e) => (
+                RecordedBody::Malformed(body.to_string()),
+                Err(ServiceError::BadInput(format!("malformed request body ({})", e))),
+            ),
+        };
+
+        self.record(id, ClientRequest { headers: HeaderMap::new(), params: raw_params.clone(), body: recorded });
+        self.record(id, ServiceMessage::Replay(original, overrides.clone()));
+
+        let params = serde_urlencoded::from_str::<RequestParams>(&raw_params)
+            .map_err(|e| ServiceError::BadInput(format!("malformed query parameters ({})", e)));
+
+        match (params, request_body) {
+            (Ok(params), Ok(request_body)) => {
+                let (response, status) = AiService::answer(id, self, params, request_body, None, &overrides).await;
+                (id, response, status)
+            }
+            (Err(e), _) | (_, Err(e)) => {
+                log::log!(target: "groan", e.log_level(), "Replay {} failed: {}", id, e);
+                self.record(id, ServiceMessage::Error(e.to_failure()));
+                (id, e.to_response(), e.status())
+            }
+        }
+    }
+
+    /// Answers requests that didn't make it to `query_service` with a `ResponseBody::error`.
+    /// Requests with bad input were already recorded along with their error;
+    /// forbidden requests aren't recorded at all, so that clients who may not use the service can't fill the console's history.
+    /// Rejections that aren't about malformed input or access (e.g. wrong path or method) are passed along.
+    async fn handle_rejection(rejection: warp::Rejection) -> Result<impl warp::Reply, warp::Rejection> {
+        let error = if let Some(InvalidInput(reason)) = rejection.find() {
+            ServiceError::BadInput(reason.clone())
+        } else if let Some(Forbidden(reason)) = rejection.find() {
+            ServiceError::Forbidden(reason.clone())
+        } else {
+            return Err(rejection);
+        };
+
+        log::log!(target: "groan", error.log_level(), "Rejected request: {}", error);
+        Ok(warp::reply::with_status(warp::reply::json(&error.to_response()), error.status()))
+    }
+
     async fn query_service(
         id: u64,
         service: Arc<AiService>,
         params: RequestParams,
-        body: RequestBody,
-    ) -> Result<ResponseBody, Box<dyn Error>> {
+        body: Arc<RequestBody>,
+        session: Option<&SessionKey>,
+        overrides: &QueryOverrides,
+    ) -> Result<ResponseBody, ServiceError> {
         match params
             .output
             .iter()
@@ -159,116 +440,594 @@ impl AiService {
             .collect::<Vec<&str>>()
             .as_slice()
         {
-            ["text", ..] => AiService::send_chat_request(id, service, params, body).await,
-            ["sound", "wav", ..] => AiService::send_sound_request(id, service, params, body).await,
-            ["image", "bmp" | "png" | "png-a", ..] => AiService::send_image_request(id, service, params, body).await,
-            _ => Ok(ResponseBody::error(format!("Unknown output format {:?}", params.output))),
+            ["text", ..] => AiService::send_chat_request(id, service, body, session, overrides).await,
+            ["sound", "wav", ..] => AiService::send_sound_request(id, service, params, body, session, overrides).await,
+            ["image", "bmp" | "png" | "png-a", ..] => AiService::send_image_request(id, service).await,
+            _ => Err(ServiceError::UnsupportedOutput(params.output.clone())),
         }
     }
 
     async fn chat_completion(
         id: u64,
         service: &Arc<AiService>,
-        params: RequestParams,
-        body: RequestBody,
-    ) -> Result<CreateChatCompletionResponse, Box<dyn Error>> {
+        body: &RequestBody,
+        context: &Context,
+        overrides: &QueryOverrides,
+    ) -> Result<CreateChatCompletionResponse, ServiceError> {
+        let request = Self::prepare_chat_request(id, service, body, context, overrides, false)?;
+        service.client.chat().create(request).await.map_err(ServiceError::Backend)
+    }
+
+    /// Builds the chat request to send to OpenAI,
+    /// recording a copy with the web console that only links to the screenshots it already has.
+    fn prepare_chat_request(
+        id: u64,
+        service: &Arc<AiService>,
+        body: &RequestBody,
+        context: &Context,
+        overrides: &QueryOverrides,
+        stream: bool,
+    ) -> Result<CreateChatCompletionRequest, ServiceError> {
+        let examples = service.examples();
+
+        let recorded = ImageUrls::Console.url(id, &body.image);
+        let mut recorded = Self::chat_request(recorded, &examples, ImageUrls::Console, context, overrides)?;
+        recorded.stream = stream.then_some(true);
+        service.record(id, recorded);
+
+        let inline = ImageUrls::Inline.url(id, &body.image);
+        let mut request = Self::chat_request(inline, &examples, ImageUrls::Inline, context, overrides)?;
+        request.stream = stream.then_some(true);
+        Ok(request)
+    }
+
+    /// Streams a chat completion, cleaning each sentence up and handing it to text-to-speech as soon as it's complete.
+    /// Stops early once the text reaches its length limits, and returns the text that was spoken,
+    /// or `None` if the model said that nothing changed since the previous narration.
+    async fn stream_sentences(
+        id: u64,
+        service: &Arc<AiService>,
+        body: &RequestBody,
+        context: &Context,
+        settings: &VoiceSettings,
+        overrides: &QueryOverrides,
+        speech: &mut SpeechTasks,
+    ) -> Result<Option<String>, ServiceError> {
+        let request = Self::prepare_chat_request(id, service, body, context, overrides, true)?;
+        let mut stream = service.client.chat().create_stream(request).await.map_err(ServiceError::Backend)?;
+
+        let mut sentences = SentenceSplitter::default();
+        let mut limiter = service.output.text.limiter();
+        let mut spoken = vec![];
+        let mut unchanged = false;
+        let mut chunks = vec![];
+        'stream: while let Some(chunk) = stream.next().await {
+            let chunk = chunk.map_err(ServiceError::Backend)?;
+            let piece = chunk.choices.first().and_then(|choice| choice.delta.content.clone()).unwrap_or_default();
+            chunks.push(chunk);
+
+            for sentence in sentences.push(&piece) {
+                let Some(sentence) = limiter.take(&sentence) else {
+                    log::debug!(target: "groan", "Request {} is as long as it may be; not waiting for the rest", id);
+                    break 'stream;
+                };
+                if session::is_no_change(&sentence) {
+                    unchanged = true;
+                } else if !sentence.is_empty() {
+                    log::debug!(target: "groan", "Speaking the next sentence of request {}", id);
+                    Self::speak(id, service, sentence.clone(), spoken.len(), settings, overrides, speech);
+                    spoken.push(sentence);
+                }
+            }
+        }
+        service.record(id, ServiceMessage::OpenAiMessage(OpenAiMessage::CreateChatCompletionStreamResponse(chunks)));
+
+        // Once the limits are reached the limiter turns down everything else, including the rest
+        let rest = sentences.finish().0.and_then(|rest| limiter.take(&rest));
+        if let Some(rest) = rest.filter(|rest| !rest.is_empty()) {
+            if session::is_no_change(&rest) {
+                unchanged = true;
+            } else {
+                Self::speak(id, service, rest.clone(), spoken.len(), settings, overrides, speech);
+                spoken.push(rest);
+            }
+        }
+
+        match (spoken.is_empty(), unchanged) {
+            (true, true) => Ok(None),
+            (true, false) => Err(ServiceError::EmptyResponse),
+            (false, _) => Ok(Some(service.output.text.join(&spoken))),
+        }
+    }
+
+    /// Starts turning some text into speech in the background, once there's a free slot for it.
+    /// The task yields `index`, the text's position in the narration, along with the WAV file it got.
+    fn speak(
+        id: u64,
+        service: &Arc<AiService>,
+        text: String,
+        index: usize,
+        settings: &VoiceSettings,
+        overrides: &QueryOverrides,
+        speech: &mut SpeechTasks,
+    ) {
+        let text = service.output.text.for_speech(&text);
+        let service = service.clone();
+        let settings = settings.clone();
+        // Replays and comparisons may try another OpenAI voice; the voice table was checked when it was loaded
+        let voice = overrides.voice.clone().or(settings.openai_voice().ok().flatten()).unwrap_or(Fable);
+
+        speech.spawn(async move {
+            let _permit = service.speech_permits.acquire().await.map_err(|e| ServiceError::TaskFailed(e.to_string()))?;
+            let bytes = match settings.backend {
+                SpeechBackend::Espeak => {
+                    let voice = settings.voice.as_deref().or(settings.fallback_voice.as_deref());
+                    service.local_speech(&text, voice, settings.speed).await?
+                }
+                SpeechBackend::Openai => match service.openai_speech(id, text.clone(), voice, settings.speed).await {
+                    Err(e) if service.output.speech.fallback => {
+                        log::warn!(target: "groan", "Falling back to espeak-ng for request {}: {}", id, e);
+                        // The voice is named for OpenAI, so espeak-ng picks one for the response's language instead
+                        service.local_speech(&text, settings.fallback_voice.as_deref(), settings.speed).await?
+                    }
+                    spoken => spoken?,
+                },
+            };
+            Ok((index, bytes))
+        });
+    }
+
+    async fn openai_speech(&self, id: u64, text: String, voice: Voice, speed: f32) -> Result<Bytes, ServiceError> {
+        let request = CreateSpeechRequestArgs::default()
+            .input(text)
+            .model(Tts1) // TODO: Make customizable
+            .voice(voice)
+            .response_format(Wav)
+            .speed(speed)
+            .build()
+            .map_err(ServiceError::Tts)?;
+
+        self.record(id, request.clone());
+        let response = self.client.audio().speech(request).await.map_err(ServiceError::Tts)?;
+        Ok(response.bytes)
+    }
+
+    async fn local_speech(&self, text: &str, voice: Option<&str>, speed: f32) -> Result<Bytes, ServiceError> {
+        self.output.speech.espeak.speak(text, voice, speed).await.map_err(ServiceError::LocalTts)
+    }
+
+    /// Builds a request asking the model to describe the screenshot at the given URL.
+    /// Any corrected examples are shown to the model first, as if it had answered them that way itself.
+    /// If there's a previous description, the model is asked to only describe what's changed since it and the changes narrated after it,
+    /// and any earlier screenshots from the session are shown just before the current one.
+    fn chat_request(
+        image_url: String,
+        examples: &[Correction],
+        urls: ImageUrls,
+        context: &Context,
+        overrides: &QueryOverrides,
+    ) -> Result<CreateChatCompletionRequest, ServiceError> {
+        let prompt = overrides.prompt.as_deref().unwrap_or(DEFAULT_PROMPT);
+        // The response is spoken with a voice for this language, so it has to be in it
+        let prompt = match &context.language {
+            Some(language) => format!(
+                "{}\nAnswer in the language with the code \"{}\", whatever language the game is in.",
+                prompt,
+                language,
+            ),
+            None => prompt.to_string(),
+        };
         let system = ChatCompletionRequestSystemMessageArgs::default()
-            .content(
-                "You are a narration service helping a visually impaired player \
-                understand the scene for the game they're playing. \
-                Describe the contents of the screenshots you will be given. \
-                Limit your response to one sentence. \
-                Do not use headings or explicit section makers. \
-                Do not speculate about the image's contents. \
-                Use video game terminology if appropriate.",
-            ) // TODO: Make customizable
+            .content(prompt)
+            .build()
+            .map(ChatCompletionRequestMessage::System)
+            .map_err(ServiceError::Backend)?;
+
+        let mut messages = vec![system];
+        for example in examples {
+            messages.push(Self::image_message(urls.url(example.id, &example.image))?);
+            messages.push(
+                ChatCompletionRequestAssistantMessageArgs::default()
+                    .content(example.text.as_str())
+                    .build()
+                    .map(ChatCompletionRequestMessage::Assistant)
+                    .map_err(ServiceError::Backend)?,
+            );
+        }
+        if let Some(memory) = &context.memory {
+            let memory = format!(
+                "What has happened in this game so far: {}\n\
+                Keep names and pronouns consistent with it, and refer to earlier events where it helps the player.",
+                memory,
+            );
+            messages.push(Self::user_message(vec![Self::text_part(memory)?])?);
+        }
+        if let Some(narration) = &context.narration {
+            let changes = if context.changes.is_empty() {
+                String::new()
+            } else {
+                let changes = context.changes.iter().map(|change| format!("\"{}\"\n", change)).collect::<String>();
+                format!("Since then you told them, oldest first:\n{}", changes)
+            };
+            let instructions = format!(
+                "You described the scene to the player as: \"{}\"\n{}\
+                Describe only what is new or different in the next screenshot, without repeating what they already know. \
+                If nothing meaningful has changed, answer with exactly {} and nothing else.",
+                narration,
+                changes,
+                session::NO_CHANGE,
+            );
+            messages.push(Self::user_message(vec![Self::text_part(instructions)?])?);
+        }
+        if !context.frames.is_empty() {
+            let mut parts = vec![Self::text_part(
+                "These are the previous screenshots from the same game, oldest first. \
+                Use them to describe motion and what's changed, but describe the screenshot after them."
+                    .to_string(),
+            )?];
+            for frame in &context.frames {
+                parts.push(Self::image_part(urls.url(frame.id, &frame.image))?);
+            }
+            messages.push(Self::user_message(parts)?);
+        }
+        messages.push(Self::image_message(image_url)?);
+
+        CreateChatCompletionRequestArgs::default()
+            .model(overrides.model.as_deref().unwrap_or(DEFAULT_MODEL))
+            .max_tokens(MAX_TOKENS)
+            .messages(messages)
+            .build()
+            .map_err(ServiceError::Backend)
+    }
+
+    fn image_message(image_url: String) -> Result<ChatCompletionRequestMessage, ServiceError> {
+        Self::user_message(vec![Self::image_part(image_url)?])
+    }
+
+    fn user_message(parts: Vec<ChatCompletionRequestMessageContentPart>) -> Result<ChatCompletionRequestMessage, ServiceError> {
+        ChatCompletionRequestUserMessageArgs::default()
+            .content(parts)
             .build()
-            .map(ChatCompletionRequestMessage::System)?;
-
-        let message = ChatCompletionRequestMessageContentPartImageArgs::default()
-            .image_url(format!(
-                "data:image/{:?};base64,{}",
-                body.format.unwrap_or(ImageOutputFormat::Png),
-                body.image
-            ))
+            .map(ChatCompletionRequestMessage::User)
+            .map_err(ServiceError::Backend)
+    }
+
+    fn image_part(image_url: String) -> Result<ChatCompletionRequestMessageContentPart, ServiceError> {
+        ChatCompletionRequestMessageContentPartImageArgs::default()
+            .image_url(image_url)
             .build()
-            .map(ChatCompletionRequestMessageContentPart::ImageUrl)?;
+            .map(ChatCompletionRequestMessageContentPart::ImageUrl)
+            .map_err(ServiceError::Backend)
+    }
+
+    fn text_part(text: String) -> Result<ChatCompletionRequestMessageContentPart, ServiceError> {
+        ChatCompletionRequestMessageContentPartTextArgs::default()
+            .text(text)
+            .build()
+            .map(ChatCompletionRequestMessageContentPart::Text)
+            .map_err(ServiceError::Backend)
+    }
+
+    /// Describes the screenshot in text, cleaned up for RetroArch,
+    /// using a person's correction instead of the model if the same screenshot was corrected before.
+    /// Returns `None` if nothing changed since the session's last narration.
+    async fn describe(
+        id: u64,
+        service: &Arc<AiService>,
+        body: &Arc<RequestBody>,
+        session: Option<&SessionKey>,
+        overrides: &QueryOverrides,
+    ) -> Result<Option<String>, ServiceError> {
+        let context = match service.recall(id, session, body) {
+            Recall::Unchanged => return Ok(None),
+            Recall::Changed(context) => context,
+        };
+
+        let text = match service.corrected_text(id, body) {
+            Some(text) => text,
+            None => {
+                let response = Self::chat_completion(id, service, body, &context, overrides).await?;
+                service.record(id, response.clone());
+                log::info!(target: "groan", "{:?}", response);
+                let text = service.output.text.clean(Self::response_text(&response)?);
+                if session::is_no_change(&text) {
+                    service.remember(id, session, context.screenshot, None);
+                    return Ok(None);
+                }
+                if text.is_empty() {
+                    return Err(ServiceError::EmptyResponse);
+                }
+                text
+            }
+        };
+
+        service.remember(id, session, context.screenshot, Some(&text));
+        service.record(id, ServiceMessage::ResponseText(text.clone()));
+        Ok(Some(text))
+    }
+
+    /// Compares the screenshot with the session's last one and gathers the session's context for the prompt.
+    /// New screenshots are kept as context for the session's next requests.
+    fn recall(self: &Arc<Self>, id: u64, session: Option<&SessionKey>, body: &Arc<RequestBody>) -> Recall {
+        let Some(key) = session.filter(|_| self.sessions.enabled()) else {
+            return Recall::Changed(Context::default());
+        };
+
+        let screenshot = session::screenshot_hash(&body.image);
+        let recall = self.sessions.recall(key, screenshot);
+        match recall {
+            Recall::Unchanged => {
+                log::info!(target: "groan", "Request {} has the same screenshot as the last one; nothing changed", id);
+                self.sessions.remember(key, screenshot, None);
+            }
+            Recall::Changed(_) => self.keep_frame(id, key, body),
+        }
+        recall
+    }
+
+    fn remember(self: &Arc<Self>, id: u64, session: Option<&SessionKey>, screenshot: u64, narration: Option<&str>) {
+        let Some(key) = session.filter(|_| self.sessions.enabled()) else {
+            return;
+        };
+
+        self.sessions.remember(key, screenshot, narration);
+        if let Some(narration) = narration.filter(|_| self.sessions.options().memory) {
+            self.update_memory(id, key, narration);
+        }
+    }
+
+    /// Folds a new narration into the session's memory in the background.
+    /// A session's updates run one at a time, each building on the last one's result, so that none of them is lost.
+    fn update_memory(self: &Arc<Self>, id: u64, key: &SessionKey, narration: &str) {
+        let service = self.clone();
+        let key = key.clone();
+        let narration = narration.to_string();
+
+        // Taken now, so that a reset while this waits for its turn still drops it
+        let Some(update) = self.sessions.memory_update(&key) else {
+            return;
+        };
+
+        tokio::spawn(async move {
+            let _turn = update.turn().await;
+            let memory = service.sessions.memory(&key);
+            let response = match Self::memory_request(memory.as_deref(), &narration) {
+                Ok(request) => service.client.chat().create(request).await.map_err(ServiceError::Backend),
+                Err(e) => Err(e),
+            };
 
-        let user = ChatCompletionRequestUserMessageArgs::default()
-            .content(vec![message])
+            match response.and_then(|response| Self::response_text(&response).cloned()) {
+                Ok(memory) => {
+                    if service.sessions.set_memory(&key, &update, memory.trim().to_string()) {
+                        log::debug!(target: "groan", "Updated the memory of {:?} after request {}: {}", key, id, memory);
+                    } else {
+                        log::debug!(target: "groan", "The memory of {:?} was reset during request {}'s update; dropping it", key, id);
+                    }
+                }
+                Err(e) => log::warn!(target: "groan", "Couldn't update the memory after request {}: {}", id, e),
+            }
+        });
+    }
+
+    fn memory_request(memory: Option<&str>, narration: &str) -> Result<CreateChatCompletionRequest, ServiceError> {
+        let system = ChatCompletionRequestSystemMessageArgs::default()
+            .content(MEMORY_PROMPT)
             .build()
-            .map(ChatCompletionRequestMessage::User)?;
+            .map(ChatCompletionRequestMessage::System)
+            .map_err(ServiceError::Backend)?;
+
+        let update = format!("Memory so far:\n{}\n\nLatest narration:\n{}", memory.unwrap_or("(nothing yet)"), narration);
+        let update = Self::user_message(vec![Self::text_part(update)?])?;
 
-        let request = CreateChatCompletionRequestArgs::default()
-            .model("gpt-4o-mini") // TODO: Make customizable
-            .max_tokens(300u32) // TODO: Make customizable
-            .messages(vec![system, user])
-            .build()?;
+        CreateChatCompletionRequestArgs::default()
+            .model(DEFAULT_MODEL)
+            .max_tokens(MAX_TOKENS)
+            .messages(vec![system, update])
+            .build()
+            .map_err(ServiceError::Backend)
+    }
 
-        service.sender.send((id, request.clone().into())).await?;
-        service.client.chat().create(request).await.or_else(|e| Err(Box::new(e))?)
+    pub(crate) fn sessions(&self) -> &Sessions {
+        &self.sessions
+    }
+
+    /// Shrinks the screenshot in the background, then keeps it to show the model with the session's next requests.
+    fn keep_frame(self: &Arc<Self>, id: u64, key: &SessionKey, body: &Arc<RequestBody>) {
+        let options = self.sessions.options();
+        if options.context_frames == 0 {
+            return;
+        }
+
+        let service = self.clone();
+        let key = key.clone();
+        let body = body.clone();
+        let size = options.frame_size;
+        tokio::task::spawn_blocking(move || match session::shrink(&body.image, size) {
+            Ok(image) => service.sessions.push_frame(&key, Frame { id, image }),
+            Err(e) => log::warn!(target: "groan", "Couldn't keep the screenshot from request {} as context: {}", id, e),
+        });
+    }
+
+    /// What to say when nothing changed, if anything; recorded as the call's response text.
+    fn no_change_message(&self, id: u64) -> Option<String> {
+        let message = self.sessions.options().no_change_message.clone();
+        log::info!(target: "groan", "Nothing changed for request {}", id);
+        self.record(id, ServiceMessage::ResponseText(message.clone().unwrap_or_default()));
+        message
+    }
+
+    /// A person's correction for this same screenshot, if corrections may stand in for the model.
+    fn corrected_text(&self, id: u64, body: &RequestBody) -> Option<String> {
+        if !self.correction_options.overrides {
+            return None;
+        }
+
+        let image = BASE64_STANDARD.decode(body.image.as_bytes()).ok()?;
+        let (corrected_id, text) = self.corrections.read().ok()?.for_image(&image).map(|c| (c.id, c.text.clone()))?;
+        log::info!(target: "groan", "Request {} has the same screenshot as request {}; using its correction", id, corrected_id);
+        self.record(id, ServiceMessage::CorrectionUsed(corrected_id));
+        Some(text)
+    }
+
+    fn examples(&self) -> Vec<Correction> {
+        match self.corrections.read() {
+            Ok(corrections) => corrections.examples(self.correction_options.few_shot),
+            Err(_) => vec![],
+        }
+    }
+
+    /// Remembers a person's correction of a call's output, replacing any earlier one for the same call.
+    pub(crate) fn set_correction(&self, correction: Correction) {
+        if let Ok(mut corrections) = self.corrections.write() {
+            corrections.insert(correction);
+        }
+    }
+
+    pub(crate) fn remove_correction(&self, id: u64) {
+        if let Ok(mut corrections) = self.corrections.write() {
+            corrections.remove(id);
+        }
+    }
+
+    /// Describes one screenshot with several profiles at once, so that their results can be compared.
+    /// None of this is recorded as a service call, since RetroArch never asked for it.
+    pub(crate) async fn compare(
+        self: Arc<Self>,
+        image: String,
+        profiles: Vec<ComparisonProfile>,
+    ) -> Vec<ComparisonResult> {
+        let mut tasks = JoinSet::new();
+        for (index, profile) in profiles.into_iter().enumerate() {
+            let request = Self::chat_request(data_url(&image), &[], ImageUrls::Inline, &Context::default(), &profile.overrides);
+            let service = self.clone();
+            tasks.spawn(async move {
+                let start = Instant::now();
+                let result = match request {
+                    Ok(request) => service.client.chat().create(request).await.map_err(ServiceError::Backend),
+                    Err(e) => Err(e),
+                };
+                let latency_ms = start.elapsed().as_millis() as u64;
+
+                let result = match result {
+                    Ok(response) => ComparisonResult {
+                        text: Self::response_text(&response).ok().cloned(),
+                        error: None,
+                        usage: response.usage,
+                        latency_ms,
+                        profile,
+                    },
+                    Err(e) => ComparisonResult {
+                        text: None,
+                        error: Some(e.to_string()),
+                        usage: None,
+                        latency_ms,
+                        profile,
+                    },
+                };
+
+                (index, result)
+            });
+        }
+
+        let mut results = Vec::with_capacity(tasks.len());
+        while let Some(joined) = tasks.join_next().await {
+            match joined {
+                Ok(result) => results.push(result),
+                Err(e) => log::error!(target: "groan", "Comparison task failed: {}", e),
+            }
+        }
+
+        // Present the results in the order the profiles were given, not the order they finished
+        results.sort_unstable_by_key(|(index, _)| *index);
+        results.into_iter().map(|(_, result)| result).collect()
+    }
+
+    fn response_text(response: &CreateChatCompletionResponse) -> Result<&String, ServiceError> {
+        response
+            .choices
+            .first()
+            .and_then(|choice| choice.message.content.as_ref())
+            .ok_or(ServiceError::EmptyResponse)
     }
 
     async fn send_chat_request(
         id: u64,
         service: Arc<AiService>,
-        params: RequestParams,
-        body: RequestBody,
-    ) -> Result<ResponseBody, Box<dyn Error>> {
-        let response = Self::chat_completion(id, &service, params, body).await?;
-        service.sender.send((id, response.clone().into())).await?;
-        log::info!(target: "groan", "{:?}", response);
-        Ok(ResponseBody::text(response.choices[0].message.content.as_ref().ok_or("No content in response")?))
+        body: Arc<RequestBody>,
+        session: Option<&SessionKey>,
+        overrides: &QueryOverrides,
+    ) -> Result<ResponseBody, ServiceError> {
+        let text = match Self::describe(id, &service, &body, session, overrides).await? {
+            Some(text) => text,
+            None => match service.no_change_message(id) {
+                Some(message) => message,
+                None => return Ok(ResponseBody::default()),
+            },
+        };
+        Ok(ResponseBody::text(service.output.text.for_display(&text)))
     }
 
     async fn send_sound_request(
         id: u64,
         service: Arc<AiService>,
         params: RequestParams,
-        body: RequestBody,
-    ) -> Result<ResponseBody, Box<dyn Error>> {
-        let chat_response = Self::chat_completion(id, &service, params, body).await?;
-        let text = chat_response.choices[0].message.content.as_ref().ok_or("No content in response")?;
+        body: Arc<RequestBody>,
+        session: Option<&SessionKey>,
+        overrides: &QueryOverrides,
+    ) -> Result<ResponseBody, ServiceError> {
+        let settings = service.output.speech.voices.for_language(params.target_lang.as_deref());
+        log::debug!(target: "groan", "Speaking request {} with {:?}", id, settings);
 
-        let request = CreateSpeechRequestArgs::default()
-            .input(text)
-            .model(Tts1) // TODO: Make customizable
-            .voice(Fable) // TODO: Make customizable
-            .response_format(Wav)
-            .speed(1.1)
-            .build()?;
-
-        // OpenAI returns a WAV file with a subchunk2 size of -1
-        // RetroArch's built-in WAV parser treats subchunks with a negative length as invalid
-        // So we need to compute the length and fix the file
-        let response = service.client.audio().speech(request).await.or_else(|e| Err(Box::new(e)))?;
-
-        // This memory is already allocated;
-        // ideally we can use it, but if not then we need to make our own copy
-        let mut sound = response.bytes.try_into_mut().unwrap_or_else(BytesMut::from);
-        let bytes_length = sound.len();
-
-        // First subchunk2 size is at bytes 40-43
-        let subchunk2size = sound.get_mut(40..44).ok_or("WAV file is too short")?;
-        if i32::from_le_bytes(subchunk2size.try_into()?) == -1 {
-            let length = (bytes_length - 44) as i32;
-            log::debug!(target: "groan", "Returned audio's subchunk2size is -1; computed size is {}", length);
-            subchunk2size.copy_from_slice(&length.to_le_bytes());
+        // Sentences are spoken as soon as they're complete, while the rest of the text is still being generated
+        let mut speech = SpeechTasks::new();
+        let narration = match service.recall(id, session, &body) {
+            Recall::Unchanged => None,
+            Recall::Changed(mut context) => {
+                let narration = match service.corrected_text(id, &body) {
+                    Some(text) => {
+                        Self::speak(id, &service, text.clone(), 0, &settings, overrides, &mut speech);
+                        Some(text)
+                    }
+                    None => {
+                        context.language = params.target_lang.clone().filter(|language| !language.trim().is_empty());
+                        Self::stream_sentences(id, &service, &body, &context, &settings, overrides, &mut speech).await?
+                    }
+                };
+                // An unchanged screenshot was already remembered when it was recalled
+                service.remember(id, session, context.screenshot, narration.as_deref());
+                narration
+            }
+        };
+
+        match narration {
+            Some(text) => service.record(id, ServiceMessage::ResponseText(text)),
+            None => {
+                let Some(message) = service.no_change_message(id) else {
+                    return Ok(ResponseBody::default());
+                };
+                // Nothing else was spoken, so the message is the whole narration
+                Self::speak(id, &service, message, 0, &settings, overrides, &mut speech);
+            }
+        }
+
+        let mut segments = Vec::with_capacity(speech.len());
+        while let Some(joined) = speech.join_next().await {
+            segments.push(joined.map_err(|e| ServiceError::TaskFailed(e.to_string()))??);
         }
+        segments.sort_unstable_by_key(|(index, _)| *index);
 
-        let bytes = sound.freeze();
+        // OpenAI's and espeak-ng's WAV files have placeholder sizes, which RetroArch's WAV parser rejects;
+        // joining the sentences' files writes a new header with the real sizes anyway,
+        // and converts any that fell back to espeak-ng to the same format as the rest
+        let segments = segments.into_iter().map(|(_, segment)| segment).collect::<Vec<_>>();
+        let bytes = Bytes::from(wav::join(&segments, &service.output.audio).map_err(ServiceError::WavFix)?);
 
         let response = ResponseBody::sound(&bytes);
-        service.sender.send((id, ServiceMessage::OpenAiMessage(OpenAiMessage::CreateSpeechResponse(bytes)))).await?;
+        service.record(id, ServiceMessage::OpenAiMessage(OpenAiMessage::CreateSpeechResponse(bytes)));
         Ok(response)
     }
 
-    async fn send_image_request(
-        id: u64,
-        service: Arc<AiService>,
-        params: RequestParams,
-        body: RequestBody,
-    ) -> Result<ResponseBody, Box<dyn Error>> {
+    async fn send_image_request(id: u64, service: Arc<AiService>) -> Result<ResponseBody, ServiceError> {
         let mut image = RgbaImage::new(32, 32);
 
         for x in 0..image.width() {
@@ -285,9 +1044,26 @@ impl AiService {
         let mut cursor = std::io::Cursor::new(Vec::<u8>::new());
         image.write_to(&mut cursor, ImageFormat::Png)?;
 
-        Ok(ResponseBody::image(&cursor.get_ref()))
+        let image = Bytes::from(cursor.into_inner());
+        let response = ResponseBody::image(&image);
+        service.record(id, ServiceMessage::ResponseImage(image));
+        Ok(response)
         // TODO: Run ocrs on the image
         // TODO: Get the bounding boxes of text in the image
         // TODO: Send an image that contains the drawn bounding boxes
     }
 }
+
+/// Inlines a base64-encoded image, labelled with the content type that its contents show.
+fn data_url(image: &str) -> String {
+    // The first few bytes are enough to tell the format, so there's no need to decode the rest
+    let head = BASE64_STANDARD.decode(image.get(..16).unwrap_or(image)).unwrap_or_default();
+    format!("data:{};base64,{}", store::image_mime_type(&head), image)
+}
+
+fn serialize_length<S>(bytes: &Bytes, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    serializer.serialize_u64(bytes.len() as u64)
+}
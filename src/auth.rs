@@ -0,0 +1,177 @@
+use base64::prelude::BASE64_STANDARD;
+use base64::Engine;
+use ipnet::IpNet;
+use percent_encoding::percent_decode_str;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use warp::http::header::AUTHORIZATION;
+use warp::http::{HeaderMap, StatusCode};
+use warp::{Filter, Rejection, Reply};
+
+/// The credentials that the web console requires, if any.
+/// Browsers send them with every request once they've been asked, including for images and the event stream,
+/// so the bundled console works with nothing but HTTP basic authentication.
+#[derive(Debug, Default)]
+pub(crate) struct ConsoleAuth {
+    /// The whole `Authorization` header that HTTP basic authentication should send.
+    basic: Option<String>,
+}
+
+/// Rejection for console requests without acceptable credentials.
+#[derive(Debug)]
+pub(crate) struct Unauthorized;
+
+impl warp::reject::Reject for Unauthorized {}
+
+impl ConsoleAuth {
+    pub(crate) fn new(user: &str, password: Option<&str>) -> Self {
+        Self {
+            basic: password.map(|password| format!("Basic {}", BASE64_STANDARD.encode(format!("{}:{}", user, password)))),
+        }
+    }
+
+    pub(crate) fn enabled(&self) -> bool {
+        self.basic.is_some()
+    }
+
+    /// Checks the `Authorization` header as sent, which needn't be UTF-8.
+    fn accepts(&self, header: Option<&[u8]>) -> bool {
+        match (&self.basic, header) {
+            (None, _) => true,
+            (Some(expected), Some(header)) => constant_time_eq(expected.as_bytes(), header),
+            (Some(_), None) => false,
+        }
+    }
+
+    /// Rejects requests with `Unauthorized` unless they carry acceptable credentials.
+    pub(crate) fn filter(self: Arc<Self>) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+        warp::header::headers_cloned()
+            .and_then(move |headers: HeaderMap| {
+                let auth = self.clone();
+                async move {
+                    if auth.accepts(headers.get(AUTHORIZATION).map(|header| header.as_bytes())) {
+                        Ok(())
+                    } else {
+                        Err(warp::reject::custom(Unauthorized))
+                    }
+                }
+            })
+            .untuple_one()
+    }
+
+    /// Turns `Unauthorized` into a response that makes browsers ask for a password.
+    pub(crate) async fn handle_rejection(rejection: Rejection) -> Result<impl Reply, Rejection> {
+        if rejection.find::<Unauthorized>().is_none() {
+            return Err(rejection);
+        }
+
+        let reply = warp::reply::with_status("Unauthorized", StatusCode::UNAUTHORIZED);
+        Ok(warp::reply::with_header(reply, "WWW-Authenticate", "Basic realm=\"groan\", charset=\"UTF-8\""))
+    }
+}
+
+/// Who may use the AI service, and thus spend the OpenAI credit.
+/// If neither is set, anyone who can reach the service may use it.
+#[derive(Debug, Default)]
+pub(crate) struct ServiceAccess {
+    /// Clients must connect from one of these networks, if any are given.
+    networks: Vec<IpNet>,
+    /// RetroArch can't send extra headers, so the token is part of the service URL that it's configured with:
+    /// either its only path segment (`http://host:4404/<token>`) or a `token` query parameter.
+    token: Option<String>,
+}
+
+/// Rejection for AI service requests from outside the allowed networks or without the right token.
+#[derive(Debug)]
+pub(crate) struct Forbidden(pub(crate) String);
+
+impl warp::reject::Reject for Forbidden {}
+
+impl ServiceAccess {
+    pub(crate) fn new(networks: Vec<IpNet>, token: Option<String>) -> Self {
+        Self { networks, token }
+    }
+
+    /// Rejects requests with `Forbidden` unless they come from an allowed network with the right token.
+    /// Extracts the raw query string with the token taken out, so that it's never recorded.
+    pub(crate) fn filter(self: Arc<Self>) -> impl Filter<Extract = (String,), Error = Rejection> + Clone {
+        let path_token = warp::path::end()
+            .map(|| None::<String>)
+            .or(warp::path::param::<String>().and(warp::path::end()).map(Some))
+            .unify();
+        let raw_query = warp::query::raw().or(warp::any().map(String::new)).unify();
+
+        warp::addr::remote()
+            .and(path_token)
+            .and(raw_query)
+            .and_then(move |remote: Option<SocketAddr>, path_token: Option<String>, raw_query: String| {
+                let access = self.clone();
+                async move {
+                    access.check(remote, path_token.as_deref(), &raw_query)?;
+                    Ok::<_, Rejection>(without_token(&raw_query))
+                }
+            })
+    }
+
+    fn check(&self, remote: Option<SocketAddr>, path_token: Option<&str>, raw_query: &str) -> Result<(), Rejection> {
+        if !self.networks.is_empty() {
+            // Clients of a dual-stack socket show up as IPv4-mapped IPv6 addresses
+            let Some(ip) = remote.map(|remote| remote.ip().to_canonical()) else {
+                return Err(warp::reject::custom(Forbidden("the client's address is unknown".into())));
+            };
+
+            if !self.networks.iter().any(|network| network.contains(&ip)) {
+                return Err(warp::reject::custom(Forbidden(format!("{} isn't in an allowed network", ip))));
+            }
+        }
+
+        let Some(expected) = &self.token else {
+            // Without a token, the service only answers at the root path as it always has
+            return match path_token {
+                Some(_) => Err(warp::reject::not_found()),
+                None => Ok(()),
+            };
+        };
+
+        // warp hands over the path segment as it was sent, so it's decoded here like the query parameter is
+        let given = path_token
+            .map(|token| percent_decode_str(token).collect::<Vec<u8>>())
+            .or_else(|| query_param(raw_query, "token").map(String::into_bytes));
+        match given {
+            Some(given) if constant_time_eq(&given, expected.as_bytes()) => Ok(()),
+            Some(_) => Err(warp::reject::custom(Forbidden("the token is wrong".into()))),
+            None => Err(warp::reject::custom(Forbidden("the token is missing".into()))),
+        }
+    }
+}
+
+/// Parses an allowed network for the command line, which may also be a single address.
+pub(crate) fn parse_network(network: &str) -> Result<IpNet, String> {
+    network
+        .parse::<IpNet>()
+        .or_else(|_| network.parse::<IpAddr>().map(IpNet::from))
+        .map_err(|_| format!("\"{}\" isn't an IP address or CIDR network", network))
+}
+
+/// The first value of a parameter in a raw query string, decoded.
+fn query_param(raw_query: &str, name: &str) -> Option<String> {
+    serde_urlencoded::from_str::<Vec<(String, String)>>(raw_query)
+        .unwrap_or_default()
+        .into_iter()
+        .find(|(param, _)| param == name)
+        .map(|(_, value)| value)
+}
+
+/// Drops the `token` parameter from a raw query string, leaving the rest exactly as the client sent it.
+fn without_token(raw_query: &str) -> String {
+    raw_query
+        .split('&')
+        .filter(|pair| *pair != "token" && !pair.starts_with("token="))
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+/// Compares secrets without leaking how much of them matched through timing.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |difference, (x, y)| difference | (x ^ y)) == 0
+}
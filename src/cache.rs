@@ -0,0 +1,267 @@
+use crate::ai::{OpenAiMessage, ServiceResponse};
+use crate::error::ServiceFailure;
+use crate::feedback::Feedback;
+use crate::web::{Artifacts, Replay, ServiceCall};
+use bytes::Bytes;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// How much history the web console may keep in memory.
+/// Any limit that's `None` isn't enforced.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct CacheLimits {
+    pub(crate) max_calls: Option<usize>,
+    pub(crate) max_bytes: Option<usize>,
+    pub(crate) max_age: Option<Duration>,
+}
+
+/// Everything recorded about one service call,
+/// kept together so that evicting a call drops all of it at once.
+struct CacheEntry {
+    call: ServiceCall,
+    image: Option<Vec<u8>>,
+    response_image: Option<Bytes>,
+    thumbnail: Option<Bytes>,
+    sound: Option<Bytes>,
+    /// Approximate memory used by this entry, in bytes.
+    size: usize,
+    created: Instant,
+    last_used: Instant,
+}
+
+/// The web console's record of recent service calls.
+/// Entries are evicted by age first, then least-recently-used first until the count and size limits are met.
+pub(crate) struct MessageCache {
+    entries: HashMap<u64, CacheEntry>,
+    limits: CacheLimits,
+    size: usize,
+    /// Calls evicted since `take_evicted` was last called.
+    evicted: Vec<u64>,
+}
+
+impl MessageCache {
+    pub(crate) fn new(limits: CacheLimits) -> Self {
+        Self {
+            entries: HashMap::new(),
+            limits,
+            size: 0,
+            evicted: vec![],
+        }
+    }
+
+    /// Calls evicted since this was last called, so that whatever else refers to them can forget them too.
+    pub(crate) fn take_evicted(&mut self) -> Vec<u64> {
+        std::mem::take(&mut self.evicted)
+    }
+
+    pub(crate) fn contains(&self, id: u64) -> bool {
+        self.entries.contains_key(&id)
+    }
+
+    /// IDs of all calls that haven't expired, in ascending order.
+    pub(crate) fn ids(&mut self) -> Vec<u64> {
+        self.evict();
+        let mut ids = self.entries.keys().cloned().collect::<Vec<_>>();
+        ids.sort_unstable();
+        ids
+    }
+
+    pub(crate) fn insert(&mut self, id: u64, call: ServiceCall, image: Option<Vec<u8>>) {
+        let now = Instant::now();
+        let size = json_size(&call) + image.as_ref().map_or(0, Vec::len);
+        let entry = CacheEntry {
+            call,
+            image,
+            response_image: None,
+            thumbnail: None,
+            sound: None,
+            size,
+            created: now,
+            last_used: now,
+        };
+
+        self.size += size;
+        if let Some(old) = self.entries.insert(id, entry) {
+            self.size -= old.size;
+        }
+
+        self.evict();
+    }
+
+    pub(crate) fn call(&mut self, id: u64) -> Option<&ServiceCall> {
+        self.touch(id).map(|entry| &entry.call)
+    }
+
+    /// Like `call`, but without counting as a use,
+    /// so that going through every call (e.g. to export them) doesn't change which are evicted first.
+    pub(crate) fn peek(&mut self, id: u64) -> Option<&ServiceCall> {
+        self.peek_entry(id).map(|entry| &entry.call)
+    }
+
+    /// Like `image`, but without counting as a use; see `peek`.
+    pub(crate) fn peek_image(&mut self, id: u64) -> Option<&Vec<u8>> {
+        self.peek_entry(id).and_then(|entry| entry.image.as_ref())
+    }
+
+    /// Like `sound`, but without counting as a use; see `peek`.
+    pub(crate) fn peek_sound(&mut self, id: u64) -> Option<&Bytes> {
+        self.peek_entry(id).and_then(|entry| entry.sound.as_ref())
+    }
+
+    pub(crate) fn image(&mut self, id: u64) -> Option<&Vec<u8>> {
+        self.touch(id).and_then(|entry| entry.image.as_ref())
+    }
+
+    pub(crate) fn response_image(&mut self, id: u64) -> Option<&Bytes> {
+        self.touch(id).and_then(|entry| entry.response_image.as_ref())
+    }
+
+    pub(crate) fn thumbnail(&mut self, id: u64) -> Option<&Bytes> {
+        self.touch(id).and_then(|entry| entry.thumbnail.as_ref())
+    }
+
+    pub(crate) fn sound(&mut self, id: u64) -> Option<&Bytes> {
+        self.touch(id).and_then(|entry| entry.sound.as_ref())
+    }
+
+    pub(crate) fn push_message(&mut self, id: u64, message: OpenAiMessage) -> bool {
+        // Speech is kept as raw bytes, not as JSON
+        let added = match &message {
+            OpenAiMessage::CreateSpeechResponse(audio) => audio.len(),
+            message => json_size(message),
+        };
+        self.update(id, added, |entry| {
+            let mut replaced = 0;
+            if let OpenAiMessage::CreateSpeechResponse(audio) = &message {
+                replaced = entry.sound.replace(audio.clone()).map_or(0, |old| old.len());
+                entry.call.artifacts.sound = Some(Artifacts::sound_url(id));
+            }
+            entry.call.openai_messages.push(message);
+            replaced
+        })
+    }
+
+    pub(crate) fn set_response_image(&mut self, id: u64, image: Bytes) -> bool {
+        let added = image.len();
+        self.update(id, added, |entry| {
+            entry.call.artifacts.image = Some(Artifacts::image_url(id));
+            entry.response_image.replace(image).map_or(0, |old| old.len())
+        })
+    }
+
+    pub(crate) fn set_response_text(&mut self, id: u64, text: String) -> bool {
+        let added = text.len();
+        self.update(id, added, |entry| {
+            entry.call.artifacts.text = Some(Artifacts::text_url(id));
+            entry.call.response_text.replace(text).map_or(0, |old| old.len())
+        })
+    }
+
+    pub(crate) fn set_thumbnail(&mut self, id: u64, thumbnail: Bytes) -> bool {
+        let added = thumbnail.len();
+        self.update(id, added, |entry| entry.thumbnail.replace(thumbnail).map_or(0, |old| old.len()))
+    }
+
+    pub(crate) fn set_replay(&mut self, id: u64, replay: Replay) -> bool {
+        let added = json_size(&replay);
+        self.update(id, added, |entry| entry.call.replay.replace(replay).map_or(0, |old| json_size(&old)))
+    }
+
+    pub(crate) fn set_response(&mut self, id: u64, response: ServiceResponse) -> bool {
+        let added = json_size(&response);
+        self.update(id, added, |entry| entry.call.client_response.replace(response).map_or(0, |old| json_size(&old)))
+    }
+
+    pub(crate) fn set_error(&mut self, id: u64, failure: ServiceFailure) -> bool {
+        let added = json_size(&failure);
+        self.update(id, added, |entry| entry.call.error.replace(failure).map_or(0, |old| json_size(&old)))
+    }
+
+    pub(crate) fn set_corrected_from(&mut self, id: u64, of: u64) -> bool {
+        self.update(id, 0, |entry| {
+            entry.call.corrected_from = Some(of);
+            0
+        })
+    }
+
+    pub(crate) fn set_feedback(&mut self, id: u64, feedback: Option<Feedback>) -> bool {
+        let added = json_size(&feedback);
+        self.update(id, added, |entry| json_size(&std::mem::replace(&mut entry.call.feedback, feedback)))
+    }
+
+    fn peek_entry(&mut self, id: u64) -> Option<&CacheEntry> {
+        self.evict();
+        self.entries.get(&id)
+    }
+
+    fn touch(&mut self, id: u64) -> Option<&mut CacheEntry> {
+        self.evict();
+        let entry = self.entries.get_mut(&id)?;
+        entry.last_used = Instant::now();
+        Some(entry)
+    }
+
+    /// Changes a call's entry, where `added` is the size of what's being added
+    /// and `update` returns the size of whatever it replaced, so that the entry's size stays accurate.
+    /// Returns `false` if there's no such call, e.g. because it was already evicted; so do all of the setters.
+    fn update<F>(&mut self, id: u64, added: usize, update: F) -> bool
+    where
+        F: FnOnce(&mut CacheEntry) -> usize,
+    {
+        let Some(entry) = self.touch(id) else {
+            return false;
+        };
+
+        let old_size = entry.size;
+        let replaced = update(entry);
+        entry.size = (old_size + added).saturating_sub(replaced);
+        let new_size = entry.size;
+        self.size = self.size - old_size + new_size;
+        self.evict();
+        true
+    }
+
+    fn evict(&mut self) {
+        if let Some(max_age) = self.limits.max_age {
+            let expired = self
+                .entries
+                .iter()
+                .filter(|(_, entry)| entry.created.elapsed() > max_age)
+                .map(|(id, _)| *id)
+                .collect::<Vec<_>>();
+
+            for id in expired {
+                self.remove(id, "expired");
+            }
+        }
+
+        while self.over_limit() {
+            let Some(id) = self.entries.iter().min_by_key(|(_, entry)| entry.last_used).map(|(id, _)| *id) else {
+                break;
+            };
+
+            self.remove(id, "least recently used");
+        }
+    }
+
+    fn over_limit(&self) -> bool {
+        self.limits.max_calls.is_some_and(|max| self.entries.len() > max)
+            || self.limits.max_bytes.is_some_and(|max| self.size > max)
+    }
+
+    fn remove(&mut self, id: u64, reason: &str) {
+        if let Some(entry) = self.entries.remove(&id) {
+            self.size -= entry.size;
+            self.evicted.push(id);
+            log::debug!(target: "groan", "Evicted request {} from the web console ({})", id, reason);
+        }
+    }
+}
+
+/// Serialized size of a value, which is a good enough estimate of what it costs to keep around.
+fn json_size<T>(value: &T) -> usize
+where
+    T: serde::Serialize,
+{
+    serde_json::to_vec(value).map_or(0, |json| json.len())
+}
@@ -0,0 +1,139 @@
+use crate::types::ResponseBody;
+use async_openai::error::OpenAIError;
+use image::ImageError;
+use log::Level;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use warp::http::StatusCode;
+
+/// Everything that can go wrong while answering an AI service request.
+#[derive(Debug)]
+pub(crate) enum ServiceError {
+    /// The request body or query parameters couldn't be understood.
+    BadInput(String),
+    /// The client isn't allowed to use the service, for the given reason.
+    Forbidden(String),
+    /// RetroArch asked for an output that groan can't produce.
+    UnsupportedOutput(Vec<String>),
+    /// The chat completion backend failed.
+    Backend(OpenAIError),
+    /// The chat completion backend answered, but without any text.
+    EmptyResponse,
+    /// The text-to-speech backend failed.
+    Tts(OpenAIError),
+    /// The local text-to-speech engine (espeak-ng) failed.
+    LocalTts(String),
+    /// The text-to-speech backend returned WAV files that couldn't be repaired, converted or joined.
+    WavFix(String),
+    /// The response image couldn't be encoded.
+    Image(ImageError),
+    /// A background task that was helping answer the request panicked or was cancelled.
+    TaskFailed(String),
+}
+
+/// What the web console records about a failed service call.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct ServiceFailure {
+    pub(crate) kind: String,
+    pub(crate) status: u16,
+    pub(crate) message: String,
+    pub(crate) detail: String,
+}
+
+impl ServiceError {
+    /// A short, stable name for this kind of error.
+    pub(crate) fn kind(&self) -> &'static str {
+        match self {
+            ServiceError::BadInput(_) => "bad_input",
+            ServiceError::Forbidden(_) => "forbidden",
+            ServiceError::UnsupportedOutput(_) => "unsupported_output",
+            ServiceError::Backend(_) => "backend",
+            ServiceError::EmptyResponse => "empty_response",
+            ServiceError::Tts(_) => "tts",
+            ServiceError::LocalTts(_) => "local_tts",
+            ServiceError::WavFix(_) => "wav_fix",
+            ServiceError::Image(_) => "image",
+            ServiceError::TaskFailed(_) => "task_failed",
+        }
+    }
+
+    pub(crate) fn status(&self) -> StatusCode {
+        match self {
+            ServiceError::BadInput(_) => StatusCode::BAD_REQUEST,
+            ServiceError::Forbidden(_) => StatusCode::FORBIDDEN,
+            ServiceError::UnsupportedOutput(_) => StatusCode::NOT_IMPLEMENTED,
+            ServiceError::Backend(_) | ServiceError::EmptyResponse | ServiceError::Tts(_) | ServiceError::WavFix(_) => StatusCode::BAD_GATEWAY,
+            ServiceError::LocalTts(_) | ServiceError::Image(_) | ServiceError::TaskFailed(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// The message shown to the player by RetroArch.
+    /// Kept short, since RetroArch displays it as an on-screen notification.
+    pub(crate) fn player_message(&self) -> String {
+        match self {
+            ServiceError::BadInput(reason) => format!("Invalid request: {}", reason),
+            // Don't say why, so that guessing the token is no easier
+            ServiceError::Forbidden(_) => "This AI service isn't available to you; check its URL".into(),
+            ServiceError::UnsupportedOutput(output) => format!("groan can't produce \"{}\" output", output.join(",")),
+            ServiceError::Backend(_) => "The AI service is unavailable right now".into(),
+            ServiceError::EmptyResponse => "The AI service didn't describe the scene".into(),
+            ServiceError::Tts(_) | ServiceError::LocalTts(_) => "Couldn't generate speech".into(),
+            ServiceError::WavFix(_) => "Couldn't prepare the generated speech".into(),
+            ServiceError::Image(_) => "Couldn't draw the response image".into(),
+            ServiceError::TaskFailed(_) => "Something went wrong inside groan".into(),
+        }
+    }
+
+    pub(crate) fn log_level(&self) -> Level {
+        match self {
+            ServiceError::BadInput(_) | ServiceError::Forbidden(_) | ServiceError::UnsupportedOutput(_) | ServiceError::EmptyResponse => Level::Warn,
+            _ => Level::Error,
+        }
+    }
+
+    pub(crate) fn to_response(&self) -> ResponseBody {
+        ResponseBody::error(self.player_message())
+    }
+
+    pub(crate) fn to_failure(&self) -> ServiceFailure {
+        ServiceFailure {
+            kind: self.kind().into(),
+            status: self.status().as_u16(),
+            message: self.player_message(),
+            detail: self.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for ServiceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ServiceError::BadInput(reason) => write!(f, "Bad input: {}", reason),
+            ServiceError::Forbidden(reason) => write!(f, "Forbidden: {}", reason),
+            ServiceError::UnsupportedOutput(output) => write!(f, "Unsupported output format {:?}", output),
+            ServiceError::Backend(e) => write!(f, "Chat completion failed: {}", e),
+            ServiceError::EmptyResponse => write!(f, "No content in chat completion response"),
+            ServiceError::Tts(e) => write!(f, "Speech synthesis failed: {}", e),
+            ServiceError::LocalTts(reason) => write!(f, "Local speech synthesis failed: {}", reason),
+            ServiceError::WavFix(reason) => write!(f, "Couldn't prepare WAV file: {}", reason),
+            ServiceError::Image(e) => write!(f, "Couldn't encode image: {}", e),
+            ServiceError::TaskFailed(reason) => write!(f, "Background task failed: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for ServiceError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ServiceError::Backend(e) | ServiceError::Tts(e) => Some(e),
+            ServiceError::Image(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<ImageError> for ServiceError {
+    fn from(error: ImageError) -> Self {
+        ServiceError::Image(error)
+    }
+}
@@ -0,0 +1,227 @@
+use crate::store::{image_extension, image_mime_type, HistoryStore};
+use base64::prelude::BASE64_STANDARD;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::io;
+use std::io::{Cursor, Write};
+use zip::write::SimpleFileOptions;
+use zip::ZipWriter;
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum ExportFormat {
+    /// One JSON object per line, with screenshots inlined as data URLs.
+    #[default]
+    Jsonl,
+    /// A zip file with the JSONL records plus each call's screenshot and sound as separate files.
+    Zip,
+    /// OpenAI's chat fine-tuning format, with one training example per call that produced text.
+    Openai,
+}
+
+/// A recorded service call and its artifacts, as the web console or history store has them.
+pub(crate) struct ExportItem {
+    pub(crate) id: u64,
+    pub(crate) call: Value,
+    pub(crate) image: Option<Vec<u8>>,
+    pub(crate) sound: Option<Vec<u8>>,
+}
+
+/// The parts of a service call that are worth training or evaluating on.
+#[derive(Debug, Serialize)]
+struct ExportRecord {
+    id: u64,
+    timestamp: Option<u64>,
+    session: Option<u64>,
+    label: Option<String>,
+    params: Option<String>,
+    model: Option<String>,
+    prompt: Option<String>,
+    output: Option<String>,
+    error: Option<Value>,
+    /// `good` or `bad`, if someone rated the output in the console.
+    rating: Option<String>,
+    /// What someone in the console said the output should have been.
+    correction: Option<String>,
+    /// A data URL in JSONL exports, or a path within the archive in zip exports.
+    image: Option<String>,
+    /// Only set in zip exports, as a path within the archive.
+    sound: Option<String>,
+}
+
+impl ExportFormat {
+    pub(crate) fn content_type(self) -> &'static str {
+        match self {
+            ExportFormat::Jsonl | ExportFormat::Openai => "application/jsonl",
+            ExportFormat::Zip => "application/zip",
+        }
+    }
+
+    pub(crate) fn file_name(self) -> &'static str {
+        match self {
+            ExportFormat::Jsonl => "groan.jsonl",
+            ExportFormat::Zip => "groan.zip",
+            ExportFormat::Openai => "groan-finetune.jsonl",
+        }
+    }
+}
+
+/// Loads every call in the history store, for exporting without a running server.
+pub(crate) async fn load_items(store: &HistoryStore) -> io::Result<Vec<ExportItem>> {
+    let mut items = vec![];
+    for id in store.ids().await? {
+        let Some(call) = store.load_call(id).await? else {
+            continue;
+        };
+
+        items.push(ExportItem {
+            id,
+            call: serde_json::from_slice(&call)?,
+            image: store.load_image(id).await?,
+            sound: store.load_sound(id).await?,
+        });
+    }
+
+    Ok(items)
+}
+
+pub(crate) fn export(items: &[ExportItem], format: ExportFormat) -> io::Result<Vec<u8>> {
+    match format {
+        ExportFormat::Jsonl => export_jsonl(items),
+        ExportFormat::Zip => export_zip(items),
+        ExportFormat::Openai => export_openai(items),
+    }
+}
+
+fn export_jsonl(items: &[ExportItem]) -> io::Result<Vec<u8>> {
+    let mut output = vec![];
+    for item in items {
+        let mut record = ExportRecord::new(item);
+        record.image = item.image.as_deref().map(data_url);
+        write_line(&mut output, &record)?;
+    }
+
+    Ok(output)
+}
+
+fn export_zip(items: &[ExportItem]) -> io::Result<Vec<u8>> {
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+    let options = SimpleFileOptions::default();
+    let mut records = vec![];
+
+    for item in items {
+        let mut record = ExportRecord::new(item);
+        if let Some(image) = &item.image {
+            let path = format!("images/{}.{}", item.id, image_extension(image));
+            zip.start_file(path.as_str(), options)?;
+            zip.write_all(image)?;
+            record.image = Some(path);
+        }
+
+        if let Some(sound) = &item.sound {
+            let path = format!("sounds/{}.wav", item.id);
+            zip.start_file(path.as_str(), options)?;
+            zip.write_all(sound)?;
+            record.sound = Some(path);
+        }
+
+        write_line(&mut records, &record)?;
+    }
+
+    zip.start_file("calls.jsonl", options)?;
+    zip.write_all(&records)?;
+
+    Ok(zip.finish()?.into_inner())
+}
+
+fn export_openai(items: &[ExportItem]) -> io::Result<Vec<u8>> {
+    let mut output = vec![];
+    for item in items {
+        let record = ExportRecord::new(item);
+        // A correction is what the model should have said; an output rated bad is what it shouldn't
+        let answer = match (&record.correction, record.rating.as_deref()) {
+            (Some(correction), _) => Some(correction),
+            (None, Some("bad")) => None,
+            (None, _) => record.output.as_ref(),
+        };
+        let (Some(image), Some(answer)) = (&item.image, answer) else {
+            continue; // Nothing to learn from
+        };
+
+        let mut messages = vec![];
+        if let Some(prompt) = &record.prompt {
+            messages.push(json!({ "role": "system", "content": prompt }));
+        }
+        messages.push(json!({
+            "role": "user",
+            "content": [{ "type": "image_url", "image_url": { "url": data_url(image) } }],
+        }));
+        messages.push(json!({ "role": "assistant", "content": answer }));
+
+        write_line(&mut output, &json!({ "messages": messages }))?;
+    }
+
+    Ok(output)
+}
+
+impl ExportRecord {
+    fn new(item: &ExportItem) -> Self {
+        let call = &item.call;
+        let request = call.pointer("/client_request");
+        let chat_request = openai_message(call, "CreateChatCompletionRequest");
+
+        Self {
+            id: item.id,
+            timestamp: call.get("timestamp").and_then(Value::as_u64),
+            session: call.get("session").and_then(Value::as_u64),
+            label: request.and_then(|r| r.pointer("/body/label")).and_then(Value::as_str).map(String::from),
+            params: request.and_then(|r| r.get("params")).and_then(Value::as_str).map(String::from),
+            model: chat_request.and_then(|r| r.get("model")).and_then(Value::as_str).map(String::from),
+            prompt: chat_request.and_then(system_prompt),
+            output: call
+                .get("response_text")
+                .filter(|text| !text.is_null())
+                .or_else(|| openai_message(call, "CreateChatCompletionResponse").and_then(|r| r.pointer("/choices/0/message/content")))
+                .and_then(Value::as_str)
+                .map(String::from),
+            error: call.get("error").filter(|e| !e.is_null()).cloned(),
+            rating: call.pointer("/feedback/rating").and_then(Value::as_str).map(String::from),
+            correction: call.pointer("/feedback/correction").and_then(Value::as_str).map(String::from),
+            image: None,
+            sound: None,
+        }
+    }
+}
+
+/// The first recorded OpenAI message of the given kind.
+pub(crate) fn openai_message<'a>(call: &'a Value, kind: &str) -> Option<&'a Value> {
+    call.get("openai_messages")?
+        .as_array()?
+        .iter()
+        .find_map(|message| message.get(kind))
+}
+
+fn system_prompt(request: &Value) -> Option<String> {
+    request
+        .get("messages")?
+        .as_array()?
+        .iter()
+        .find(|message| message.get("role").and_then(Value::as_str) == Some("system"))?
+        .get("content")?
+        .as_str()
+        .map(String::from)
+}
+
+fn data_url(image: &[u8]) -> String {
+    format!("data:{};base64,{}", image_mime_type(image), BASE64_STANDARD.encode(image))
+}
+
+fn write_line<T>(output: &mut Vec<u8>, value: &T) -> io::Result<()>
+where
+    T: Serialize,
+{
+    serde_json::to_writer(&mut *output, value)?;
+    output.push(b'\n');
+    Ok(())
+}
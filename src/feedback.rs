@@ -0,0 +1,110 @@
+use base64::prelude::BASE64_STANDARD;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{Hash, Hasher};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Rating {
+    Good,
+    Bad,
+}
+
+/// A person's judgement of a recorded call's output, as entered in the web console.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub(crate) struct Feedback {
+    #[serde(default)]
+    pub(crate) rating: Option<Rating>,
+    /// What the output should have been.
+    #[serde(default)]
+    pub(crate) correction: Option<String>,
+}
+
+/// How the AI service should use corrected outputs.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct CorrectionOptions {
+    /// Answer with the correction instead of asking the model when the exact same screenshot comes in again.
+    pub(crate) overrides: bool,
+    /// How many of the most recent corrections to show the model as examples.
+    pub(crate) few_shot: usize,
+    /// The most corrections to keep in memory; the oldest are forgotten first.
+    pub(crate) limit: usize,
+}
+
+/// A screenshot and the text that a person said describes it best.
+#[derive(Debug, Clone)]
+pub(crate) struct Correction {
+    /// The call that was corrected.
+    pub(crate) id: u64,
+    /// The screenshot, base64-encoded for the model.
+    pub(crate) image: String,
+    /// A hash of the decoded screenshot, since the same image may be base64-encoded in more than one way.
+    image_hash: u64,
+    pub(crate) text: String,
+}
+
+impl Correction {
+    pub(crate) fn new(id: u64, image: &[u8], text: String) -> Self {
+        Self { id, image: BASE64_STANDARD.encode(image), image_hash: image_hash(image), text }
+    }
+}
+
+/// Every known correction, indexed by call ID and by screenshot.
+#[derive(Debug)]
+pub(crate) struct Corrections {
+    by_id: BTreeMap<u64, Correction>,
+    by_image: HashMap<u64, u64>,
+    limit: usize,
+}
+
+impl Corrections {
+    /// Keeps up to `limit` corrections, forgetting the oldest ones to make room for new ones.
+    pub(crate) fn new(limit: usize) -> Self {
+        Self { by_id: BTreeMap::new(), by_image: HashMap::new(), limit }
+    }
+
+    pub(crate) fn insert(&mut self, correction: Correction) {
+        self.remove(correction.id);
+        self.by_image.insert(correction.image_hash, correction.id);
+        self.by_id.insert(correction.id, correction);
+
+        while self.by_id.len() > self.limit {
+            let Some(oldest) = self.by_id.keys().next().copied() else {
+                break;
+            };
+            self.remove(oldest);
+        }
+    }
+
+    pub(crate) fn remove(&mut self, id: u64) {
+        if let Some(old) = self.by_id.remove(&id) {
+            if self.by_image.get(&old.image_hash) == Some(&id) {
+                self.by_image.remove(&old.image_hash);
+            }
+        }
+    }
+
+    /// The correction for a screenshot identical to this one (decoded), if there is one.
+    pub(crate) fn for_image(&self, image: &[u8]) -> Option<&Correction> {
+        let id = self.by_image.get(&image_hash(image))?;
+        // Rule out a hash collision before answering with someone else's correction
+        self.by_id
+            .get(id)
+            .filter(|correction| BASE64_STANDARD.decode(&correction.image).is_ok_and(|corrected| corrected == image))
+    }
+
+    /// Up to `count` of the most recent corrections, oldest first.
+    pub(crate) fn examples(&self, count: usize) -> Vec<Correction> {
+        let mut examples = self.by_id.values().rev().take(count).cloned().collect::<Vec<_>>();
+        examples.reverse();
+        examples
+    }
+}
+
+pub(crate) fn image_hash(image: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    image.hash(&mut hasher);
+    hasher.finish()
+}
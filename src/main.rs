@@ -1,34 +1,259 @@
 mod ai;
+mod auth;
+mod cache;
+mod error;
+mod export;
+mod feedback;
+mod search;
+mod session;
+mod stats;
+mod store;
+mod text;
+mod tts;
 mod types;
+mod wav;
 mod web;
 
-use crate::ai::AiService;
+use crate::ai::{AiService, OutputOptions};
+use crate::auth::{ConsoleAuth, ServiceAccess};
+use crate::cache::CacheLimits;
+use crate::export::ExportFormat;
+use crate::feedback::CorrectionOptions;
+use crate::session::{SessionOptions, Sessions};
+use crate::stats::PipelineStats;
+use crate::store::HistoryStore;
+use crate::text::TextOptions;
+use crate::tts::{Espeak, SpeechBackend, SpeechOptions, VoiceSettings, VoiceTable};
+use crate::wav::AudioOptions;
 use crate::web::WebConsoleService;
 use async_openai::config::OpenAIConfig;
 use async_openai::Client;
-use clap::Parser;
+use clap::builder::RangedU64ValueParser;
+use clap::{Parser, Subcommand};
+use ipnet::IpNet;
 use std::net::{IpAddr, Ipv4Addr};
+use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
 // NOTE: These doc comments are parsed and embedded into the CLI itself.
 
 /// groan - Good RetroArch OpenAI iNtegration
 #[derive(Parser, Debug)]
 #[command(version, about, long_about)]
 struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// The API key used to authenticate with OpenAI.
     /// Provide on the command-line or with the OPENAI_API_KEY environment variable.
+    /// Required unless running a subcommand.
     #[arg(short, long, env = "OPENAI_API_KEY")]
-    key: String,
+    key: Option<String>,
 
+    /// The address that the AI service listens on.
     #[arg(short, long, default_value_t = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)))]
     ip: IpAddr,
 
     #[arg(short, long, default_value_t = 4404)]
     port: u16,
 
-    // TODO: Allow the console to bind on a separate interface
+    /// Only answer AI service requests from this address or CIDR network.
+    /// May be given more than once; if never given, any client may use the service.
+    #[arg(long = "allow", value_name = "NETWORK", value_parser = auth::parse_network)]
+    allowed_networks: Vec<IpNet>,
+
+    /// If given, AI service requests must include this token,
+    /// either as the service URL's path (`http://host:4404/<token>`) or as its `token` query parameter.
+    #[arg(long, env = "GROAN_SERVICE_TOKEN")]
+    service_token: Option<String>,
+
+    /// The address that the web console listens on.
+    /// Kept separate from --ip so that RetroArch can reach the AI service over the LAN
+    /// while the console stays on this machine.
+    #[arg(long, default_value_t = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)))]
+    console_ip: IpAddr,
+
     #[arg(short, long, default_value_t = 4405)]
     console_port: u16,
+
+    /// The user name for the web console's basic authentication.
+    #[arg(long, default_value = "groan")]
+    console_user: String,
+
+    /// If given, the web console requires this password through basic authentication.
+    #[arg(long, env = "GROAN_CONSOLE_PASSWORD")]
+    console_password: Option<String>,
+
+    /// The most service calls the web console will remember.
+    #[arg(long, default_value_t = 1000)]
+    history_max_calls: usize,
+
+    /// The most memory (in bytes, approximately) that the web console's history may use.
+    #[arg(long, default_value_t = 256 * 1024 * 1024)]
+    history_max_bytes: usize,
+
+    /// How long (in seconds) the web console remembers each service call.
+    /// If not given, calls are only forgotten to stay within the other limits.
+    #[arg(long)]
+    history_max_age: Option<u64>,
+
+    /// How many messages about service calls may wait for the web console to record them.
+    /// If the console falls further behind, messages are dropped so that players aren't kept waiting.
+    #[arg(long, default_value_t = 256, value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+    console_queue: usize,
+
+    /// A directory in which to save every service call, so that history survives restarts.
+    /// If not given, history is only kept in memory.
+    #[arg(long, global = true)]
+    history_dir: Option<PathBuf>,
+
+    /// When a screenshot identical to one that was corrected in the web console comes in,
+    /// answer with the correction instead of asking the model.
+    #[arg(long)]
+    correction_overrides: bool,
+
+    /// How many of the most recent corrections from the web console to show the model as examples.
+    /// Each example adds a screenshot to every request, so keep this small.
+    #[arg(long, default_value_t = 0)]
+    few_shot: usize,
+
+    /// The most corrections from the web console to keep in memory for --correction-overrides and --few-shot.
+    /// The oldest are forgotten first.
+    #[arg(long, default_value_t = 100, value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+    max_corrections: usize,
+
+    /// Resample spoken responses to this rate (in Hz), e.g. 44100 or 48000.
+    /// If not given, they keep the text-to-speech backend's rate.
+    #[arg(long, value_parser = clap::value_parser!(u32).range(8000..=192000))]
+    audio_sample_rate: Option<u32>,
+
+    /// Convert spoken responses to this many channels (1 for mono, 2 for stereo).
+    #[arg(long, value_parser = clap::value_parser!(u16).range(1..=2))]
+    audio_channels: Option<u16>,
+
+    /// Bring every spoken response to this loudness (RMS level in dBFS, e.g. -20),
+    /// so that they all sound about as loud.
+    #[arg(long, allow_negative_numbers = true)]
+    audio_loudness: Option<f32>,
+
+    /// The engine that speaks responses when RetroArch asks for sound,
+    /// unless --voices says otherwise for the response's language.
+    #[arg(long, value_enum, default_value_t = SpeechBackend::Openai)]
+    speech_backend: SpeechBackend,
+
+    /// How fast responses are spoken, as a multiple of the engine's normal rate,
+    /// unless --voices says otherwise for the response's language.
+    #[arg(long, default_value_t = 1.1)]
+    speech_speed: f32,
+
+    /// A JSON file that picks the speech engine, voice and speed for each language RetroArch asks for,
+    /// e.g. `{"ja": {"backend": "espeak", "voice": "ja"}, "default": {"voice": "nova", "speed": 1.2}}`.
+    /// Languages it doesn't list use its `default` entry, or else --speech-backend and --speech-speed;
+    /// entries that leave out the backend or speed take them from there too.
+    #[arg(long)]
+    voices: Option<PathBuf>,
+
+    /// If OpenAI's text-to-speech fails, speak with espeak-ng instead.
+    #[arg(long)]
+    speech_fallback: bool,
+
+    /// How many text-to-speech requests may run at once, across every client.
+    /// Long responses are spoken a sentence at a time, so each one can make several.
+    #[arg(long, default_value_t = 4, value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+    speech_concurrency: usize,
+
+    /// The espeak-ng program to run for local speech.
+    #[arg(long, default_value = "espeak-ng")]
+    espeak_command: PathBuf,
+
+    /// The espeak-ng voice to use, e.g. `en-us`, when neither --voices nor RetroArch's target language names one.
+    /// If not given, espeak-ng uses its own default.
+    #[arg(long)]
+    espeak_voice: Option<String>,
+
+    /// Leave markdown formatting in responses instead of removing it.
+    #[arg(long)]
+    keep_markdown: bool,
+
+    /// Leave emoji in responses instead of removing them.
+    #[arg(long)]
+    keep_emoji: bool,
+
+    /// Leave line breaks and runs of spaces in responses instead of collapsing them into single spaces.
+    #[arg(long)]
+    keep_whitespace: bool,
+
+    /// Cut responses off after this many sentences.
+    #[arg(long, value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+    max_sentences: Option<usize>,
+
+    /// Cut responses off after this many characters, at a word boundary if possible.
+    #[arg(long, value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+    max_chars: Option<usize>,
+
+    /// Wrap on-screen responses into lines of at most this many characters.
+    #[arg(long, value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+    wrap_width: Option<usize>,
+
+    /// Don't spell out common abbreviations (such as "HP" or "e.g.") before speaking responses.
+    /// Any given with --abbreviation are still spelled out.
+    #[arg(long)]
+    no_default_abbreviations: bool,
+
+    /// An abbreviation to spell out before speaking responses, as FROM=TO (e.g. `KO=knocked out`).
+    /// May be given more than once.
+    #[arg(long = "abbreviation", value_name = "FROM=TO", value_parser = text::parse_abbreviation)]
+    abbreviations: Vec<(String, String)>,
+
+    /// Only narrate what changed since the last narration for the same client and game,
+    /// instead of describing the whole screen every time.
+    #[arg(long)]
+    changes_only: bool,
+
+    /// What to say with --changes-only when nothing changed.
+    /// Give an empty message to say nothing at all.
+    #[arg(long, default_value = "No change.")]
+    no_change_message: String,
+
+    /// How many earlier screenshots from the same client and game to show the model along with each new one,
+    /// so that it can describe motion and what's changed.
+    /// Each one adds to the cost of every request, so keep this small.
+    #[arg(long, default_value_t = 0)]
+    context_frames: usize,
+
+    /// The largest width or height (in pixels) of the earlier screenshots given by --context-frames;
+    /// larger ones are shrunk to fit, which makes them cheaper.
+    #[arg(long, default_value_t = 512, value_parser = clap::value_parser!(u32).range(16..))]
+    context_frame_size: u32,
+
+    /// Keep a running summary of what's happened in each client's game
+    /// (characters met, location, objectives), and show it to the model with every request
+    /// so that names stay consistent and narrations can refer to earlier events.
+    /// Each narration costs an extra, text-only request to update the summary.
+    /// The summary outlives --session-timeout, so a game picks up where it left off after a break;
+    /// it's only forgotten when it's reset from the console or groan restarts.
+    #[arg(long)]
+    story_memory: bool,
+
+    /// How long (in seconds) a client may go without requests
+    /// before its last narration and earlier screenshots are forgotten.
+    #[arg(long, default_value_t = 600)]
+    session_timeout: u64,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Export the service calls saved in --history-dir as a dataset, instead of running the server.
+    Export {
+        #[arg(short, long, value_enum, default_value_t = ExportFormat::Jsonl)]
+        format: ExportFormat,
+
+        /// Where to write the dataset.
+        /// If not given, it's written to standard output.
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
 }
 
 #[tokio::main]
@@ -36,25 +261,130 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let cli = Cli::parse();
     pretty_env_logger::init();
 
+    if let Some(Command::Export { format, output }) = &cli.command {
+        return run_export(cli.history_dir.as_ref(), *format, output.as_ref()).await;
+    }
+
+    let key = cli.key.ok_or("An OpenAI API key is required; provide --key or set OPENAI_API_KEY")?;
+    let shares_address = cli.ip == cli.console_ip || cli.ip.is_unspecified() || cli.console_ip.is_unspecified();
+    if shares_address && cli.port == cli.console_port {
+        return Err(format!("The AI service and the web console can't both listen on port {}", cli.port).into());
+    }
+
+    if !cli.ip.is_loopback() && cli.allowed_networks.is_empty() && cli.service_token.is_none() {
+        log::warn!(target: "groan", "Anyone who can reach {} can use the AI service; consider --allow or --service-token", cli.ip);
+    }
+    let service_access = Arc::new(ServiceAccess::new(cli.allowed_networks, cli.service_token));
+    let console_auth = ConsoleAuth::new(&cli.console_user, cli.console_password.as_deref());
+    if !cli.console_ip.is_loopback() && !console_auth.enabled() {
+        log::warn!(target: "groan", "The web console is reachable from {} without a password; consider --console-password", cli.console_ip);
+    }
+
     let client = Arc::new(Client::with_config(
-        OpenAIConfig::new().with_api_key(cli.key),
+        OpenAIConfig::new().with_api_key(key),
     ));
 
     // Do a basic query just to make sure the key is okay
     let _ = client.models().list().await?;
     // TODO: Make the exit printout look nicer
-    // TODO: Validate that the ports aren't equal
 
-    let (sender, receiver) = tokio::sync::mpsc::channel(32);
-    let ai_service = AiService::service(client, sender);
-    let web_service = WebConsoleService::new();
+    let (sender, receiver) = tokio::sync::mpsc::channel(cli.console_queue);
+    let store = match &cli.history_dir {
+        Some(dir) => Some(Arc::new(HistoryStore::open(dir).await?)),
+        None => None,
+    };
+    let first_id = match &store {
+        Some(store) => store.next_id().await?,
+        None => 0,
+    };
+
+    let stats = Arc::new(PipelineStats::default());
+    let correction_options = CorrectionOptions {
+        overrides: cli.correction_overrides,
+        few_shot: cli.few_shot,
+        limit: cli.max_corrections,
+    };
+    let audio_options = AudioOptions {
+        sample_rate: cli.audio_sample_rate,
+        channels: cli.audio_channels,
+        loudness: cli.audio_loudness,
+    };
+    let default_voice = VoiceSettings::new(cli.speech_backend, cli.speech_speed);
+    let voices = match &cli.voices {
+        Some(path) => VoiceTable::load(path, default_voice).await?,
+        None => VoiceTable::new(default_voice)?,
+    };
+    let speech_options = SpeechOptions {
+        voices,
+        fallback: cli.speech_fallback,
+        concurrency: cli.speech_concurrency,
+        espeak: Espeak {
+            command: cli.espeak_command,
+            voice: cli.espeak_voice,
+        },
+    };
+    if speech_options.voices.uses(SpeechBackend::Espeak) || speech_options.fallback {
+        if let Err(e) = speech_options.espeak.check().await {
+            log::warn!(target: "groan", "Local speech won't work: {}", e);
+        }
+    }
+    let mut abbreviations = vec![];
+    if !cli.no_default_abbreviations {
+        abbreviations.extend(text::DEFAULT_ABBREVIATIONS.iter().map(|(from, to)| (from.to_string(), to.to_string())));
+    }
+    abbreviations.extend(cli.abbreviations);
+    let text_options = TextOptions {
+        strip_markdown: !cli.keep_markdown,
+        strip_emoji: !cli.keep_emoji,
+        collapse_whitespace: !cli.keep_whitespace,
+        max_sentences: cli.max_sentences,
+        max_chars: cli.max_chars,
+        wrap_width: cli.wrap_width,
+        abbreviations,
+    };
+    let output_options = OutputOptions {
+        text: text_options,
+        speech: speech_options,
+        audio: audio_options,
+    };
+    let sessions = Sessions::new(SessionOptions {
+        changes_only: cli.changes_only,
+        no_change_message: Some(cli.no_change_message).filter(|message| !message.trim().is_empty()),
+        memory: cli.story_memory,
+        context_frames: cli.context_frames,
+        frame_size: cli.context_frame_size,
+        timeout: Duration::from_secs(cli.session_timeout),
+    });
+    let ai = AiService::new(client, sender, stats.clone(), first_id, correction_options, output_options, sessions);
+    let limits = CacheLimits {
+        max_calls: Some(cli.history_max_calls),
+        max_bytes: Some(cli.history_max_bytes),
+        max_age: cli.history_max_age.map(Duration::from_secs),
+    };
+    let web_service = WebConsoleService::new(ai.clone(), limits, store, stats, console_auth);
+    web_service.load_corrections().await;
     let mut web_service_poller = web_service.clone();
     
     tokio::join!(
-        warp::serve(ai_service).run((cli.ip, cli.port)),
-        warp::serve(web_service.server_filter()).run((cli.ip, cli.console_port)),
+        warp::serve(ai.service(service_access)).run((cli.ip, cli.port)),
+        warp::serve(web_service.server_filter()).run((cli.console_ip, cli.console_port)),
         web_service_poller.poll_task(receiver),
     );
 
     Ok(())
 }
+
+async fn run_export(history_dir: Option<&PathBuf>, format: ExportFormat, output: Option<&PathBuf>) -> Result<(), Box<dyn std::error::Error>> {
+    let history_dir = history_dir.ok_or("Exporting requires --history-dir")?;
+    let store = HistoryStore::open(history_dir).await?;
+    let items = export::load_items(&store).await?;
+    let dataset = export::export(&items, format)?;
+
+    match output {
+        Some(path) => tokio::fs::write(path, dataset).await?,
+        None => tokio::io::stdout().write_all(&dataset).await?,
+    }
+
+    log::info!(target: "groan", "Exported {} service calls", items.len());
+    Ok(())
+}
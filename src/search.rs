@@ -0,0 +1,152 @@
+use crate::export::openai_message;
+use serde::Deserialize;
+use serde_json::Value;
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum SortOrder {
+    #[default]
+    Oldest,
+    Newest,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum CallStatus {
+    Success,
+    Error,
+}
+
+/// Which recorded calls `/api/request` should list, and which page of them.
+/// Every filter that's given must match.
+#[derive(Debug, Default, Deserialize)]
+pub(crate) struct RequestQuery {
+    #[serde(default)]
+    pub(crate) offset: usize,
+    /// If not given, every call from `offset` onwards is listed.
+    pub(crate) limit: Option<usize>,
+    #[serde(default)]
+    pub(crate) order: SortOrder,
+    /// Only calls that asked for this kind of output, e.g. `text` or `sound`.
+    pub(crate) output: Option<String>,
+    /// Only calls whose label contains this, ignoring case.
+    pub(crate) label: Option<String>,
+    pub(crate) status: Option<CallStatus>,
+    /// Only calls that were sent to exactly this model.
+    pub(crate) model: Option<String>,
+    /// Only calls whose output, correction or error message contains this, ignoring case.
+    pub(crate) q: Option<String>,
+}
+
+/// The parts of a call that `RequestQuery` filters on,
+/// kept for every call so that listing calls doesn't mean loading and parsing each one again.
+#[derive(Debug, Clone)]
+pub(crate) struct CallSummary {
+    /// Every kind of output that the call asked for.
+    outputs: Vec<String>,
+    label: String,
+    failed: bool,
+    /// The model that the call was sent to, if it got that far.
+    model: Option<String>,
+    /// Everything in the call that a person might want to find by searching for some text, in lowercase.
+    text: Vec<String>,
+}
+
+impl CallSummary {
+    pub(crate) fn new(call: &Value) -> Self {
+        let request = call.get("client_request");
+        let params = request.and_then(|r| r.get("params")).and_then(Value::as_str).unwrap_or_default();
+        let outputs = serde_urlencoded::from_str::<Vec<(String, String)>>(params)
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|(name, _)| name == "output")
+            .flat_map(|(_, value)| value.split(',').map(|output| output.trim().to_string()).collect::<Vec<_>>())
+            .collect();
+
+        Self {
+            outputs,
+            label: request.and_then(|r| r.pointer("/body/label")).and_then(Value::as_str).unwrap_or_default().to_string(),
+            failed: call.get("error").is_some_and(|error| !error.is_null()),
+            model: openai_message(call, "CreateChatCompletionRequest")
+                .and_then(|r| r.get("model"))
+                .and_then(Value::as_str)
+                .map(String::from),
+            text: searchable_text(call).map(str::to_lowercase).collect(),
+        }
+    }
+}
+
+impl RequestQuery {
+    /// Whether any call could be left out, so that the caller knows if it has to check them.
+    pub(crate) fn filters(&self) -> bool {
+        self.output.is_some() || self.label.is_some() || self.status.is_some() || self.model.is_some() || self.q.is_some()
+    }
+
+    pub(crate) fn matches(&self, call: &CallSummary) -> bool {
+        if let Some(output) = &self.output {
+            if !call.outputs.iter().any(|requested| requested.eq_ignore_ascii_case(output)) {
+                return false;
+            }
+        }
+
+        if let Some(label) = &self.label {
+            if !call.label.to_lowercase().contains(&label.to_lowercase()) {
+                return false;
+            }
+        }
+
+        if let Some(status) = self.status {
+            if call.failed != matches!(status, CallStatus::Error) {
+                return false;
+            }
+        }
+
+        if let Some(model) = &self.model {
+            if call.model.as_deref() != Some(model.as_str()) {
+                return false;
+            }
+        }
+
+        if let Some(text) = &self.q {
+            let text = text.to_lowercase();
+            if !call.text.iter().any(|searchable| searchable.contains(&text)) {
+                return false;
+            }
+        }
+
+        true
+    }
+
+    /// Sorts the IDs of matching calls and picks out the requested page.
+    /// IDs are handed out in the order that requests arrive, so sorting by ID sorts by time.
+    pub(crate) fn page(&self, mut ids: Vec<u64>) -> Vec<u64> {
+        ids.sort_unstable();
+        if let SortOrder::Newest = self.order {
+            ids.reverse();
+        }
+
+        ids.into_iter()
+            .skip(self.offset)
+            .take(self.limit.unwrap_or(usize::MAX))
+            .collect()
+    }
+}
+
+/// Everything in a call that a person might want to find by searching for some text.
+fn searchable_text(call: &Value) -> impl Iterator<Item = &str> {
+    let choices = openai_message(call, "CreateChatCompletionResponse")
+        .and_then(|response| response.get("choices"))
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .filter_map(|choice| choice.pointer("/message/content"));
+
+    let others = [
+        call.get("response_text"),
+        call.pointer("/client_response/body/text"),
+        call.pointer("/feedback/correction"),
+        call.pointer("/error/message"),
+    ];
+
+    choices.chain(others.into_iter().flatten()).filter_map(Value::as_str)
+}
@@ -0,0 +1,332 @@
+use base64::prelude::BASE64_STANDARD;
+use base64::Engine;
+use crate::feedback;
+use image::ImageFormat;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::io::Cursor;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::MutexGuard;
+
+/// What the model is asked to answer with when a screenshot shows nothing new.
+pub(crate) const NO_CHANGE: &str = "NO_CHANGE";
+
+/// How many narrations of what changed are kept after a session's last full description.
+const MAX_CHANGES: usize = 5;
+
+/// Identifies whose narration a request continues: one RetroArch instance playing one game.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub(crate) struct SessionKey {
+    pub(crate) client: IpAddr,
+    /// The content label that RetroArch sends with every request.
+    pub(crate) label: String,
+}
+
+/// How narration carries over from one request to the next.
+#[derive(Debug, Clone)]
+pub(crate) struct SessionOptions {
+    /// Only narrate what changed since the last narration.
+    pub(crate) changes_only: bool,
+    /// What to say when nothing changed; if `None`, nothing is said at all.
+    pub(crate) no_change_message: Option<String>,
+    /// Keep a running summary of what's happened in each game, and show it to the model.
+    pub(crate) memory: bool,
+    /// How many earlier screenshots to show the model along with the current one.
+    pub(crate) context_frames: usize,
+    /// The largest width or height (in pixels) of the earlier screenshots; larger ones are shrunk to fit.
+    pub(crate) frame_size: u32,
+    /// How long a session may go without requests before it's forgotten.
+    /// Its memory is kept regardless, so that a game can be picked up again after a break.
+    pub(crate) timeout: Duration,
+}
+
+/// An earlier screenshot, kept to give the model context.
+#[derive(Debug, Clone)]
+pub(crate) struct Frame {
+    /// The call that the screenshot came from.
+    pub(crate) id: u64,
+    /// The shrunken screenshot, base64-encoded as PNG.
+    pub(crate) image: String,
+}
+
+/// What a request's session contributes to the prompt.
+#[derive(Debug, Default)]
+pub(crate) struct Context {
+    /// The last full description of the scene, if only changes are being narrated.
+    pub(crate) narration: Option<String>,
+    /// What's been narrated since that description, oldest first.
+    pub(crate) changes: Vec<String>,
+    /// Earlier screenshots, oldest first.
+    pub(crate) frames: Vec<Frame>,
+    /// A summary of what's happened in the game so far, if memory is on.
+    pub(crate) memory: Option<String>,
+    /// The language code that the response should be in, e.g. `ja`, if RetroArch asked for spoken responses in one.
+    pub(crate) language: Option<String>,
+    /// The screenshot's `screenshot_hash`, for remembering it once it's been narrated.
+    pub(crate) screenshot: u64,
+}
+
+/// What groan remembers about a session between requests.
+#[derive(Debug)]
+struct Session {
+    /// The `screenshot_hash` of the last screenshot seen, which is all it takes to tell whether the next one is the same.
+    screenshot: Option<u64>,
+    /// The last full description of the scene, which may be for an earlier screenshot if nothing changed since.
+    narration: Option<String>,
+    /// The most recent narrations of what changed since that description, oldest first.
+    changes: VecDeque<String>,
+    /// The most recent screenshots, oldest first.
+    frames: VecDeque<Frame>,
+    last_seen: Instant,
+}
+
+/// A summary of what's happened in a session's game so far.
+/// It's kept apart from the session, so that it outlives the session's timeout.
+#[derive(Debug, Default)]
+struct Memory {
+    summary: Option<String>,
+    /// Held while the memory is being updated, so that each update builds on the last one's result.
+    lock: Arc<tokio::sync::Mutex<()>>,
+    /// How many times the memory has been reset, so that updates started before a reset can be dropped.
+    generation: u64,
+}
+
+/// A pending update of a session's memory.
+/// Updates take turns, and one is dropped if the memory was reset after it started.
+#[derive(Debug)]
+pub(crate) struct MemoryUpdate {
+    lock: Arc<tokio::sync::Mutex<()>>,
+    generation: u64,
+}
+
+/// A session as the web console shows it.
+#[derive(Debug, Serialize)]
+pub(crate) struct SessionSummary {
+    #[serde(flatten)]
+    pub(crate) key: SessionKey,
+    pub(crate) narration: Option<String>,
+    pub(crate) changes: Vec<String>,
+    pub(crate) memory: Option<String>,
+    pub(crate) frames: usize,
+    /// How long ago (in seconds) the session's last request came in.
+    pub(crate) idle: u64,
+}
+
+/// What's known about a request's session before it's narrated.
+#[derive(Debug)]
+pub(crate) enum Recall {
+    /// The screenshot is identical to the last one, so there's nothing to narrate.
+    Unchanged,
+    /// The screenshot is new; holds whatever the session has to build on.
+    Changed(Context),
+}
+
+#[derive(Debug)]
+pub(crate) struct Sessions {
+    options: SessionOptions,
+    sessions: Mutex<HashMap<SessionKey, Session>>,
+    /// Every session's memory, which isn't forgotten with the session.
+    memories: Mutex<HashMap<SessionKey, Memory>>,
+}
+
+impl Sessions {
+    pub(crate) fn new(options: SessionOptions) -> Self {
+        Self { options, sessions: Mutex::new(HashMap::new()), memories: Mutex::new(HashMap::new()) }
+    }
+
+    pub(crate) fn options(&self) -> &SessionOptions {
+        &self.options
+    }
+
+    /// Whether anything carries over between requests at all.
+    pub(crate) fn enabled(&self) -> bool {
+        self.options.changes_only || self.options.memory || self.options.context_frames > 0
+    }
+
+    /// Compares a screenshot with the session's last one, and gathers what the session has to build on.
+    pub(crate) fn recall(&self, key: &SessionKey, screenshot: u64) -> Recall {
+        let new = Context { screenshot, ..Context::default() };
+        let Ok(mut sessions) = self.sessions.lock() else {
+            return Recall::Changed(new);
+        };
+        self.forget_stale(&mut sessions);
+
+        let Some(session) = sessions.get(key) else {
+            return Recall::Changed(new);
+        };
+        if self.options.changes_only && session.screenshot == Some(screenshot) {
+            return Recall::Unchanged;
+        }
+
+        Recall::Changed(Context {
+            narration: session.narration.clone().filter(|_| self.options.changes_only),
+            changes: session.changes.iter().cloned().collect(),
+            frames: session.frames.iter().cloned().collect(),
+            memory: self.memory(key).filter(|_| self.options.memory),
+            ..new
+        })
+    }
+
+    /// Starts updating the session's memory with a new narration; see `MemoryUpdate`.
+    pub(crate) fn memory_update(&self, key: &SessionKey) -> Option<MemoryUpdate> {
+        let mut memories = self.memories.lock().ok()?;
+        let memory = memories.entry(key.clone()).or_default();
+        Some(MemoryUpdate { lock: memory.lock.clone(), generation: memory.generation })
+    }
+
+    /// The session's memory, for updating it with a new narration.
+    pub(crate) fn memory(&self, key: &SessionKey) -> Option<String> {
+        self.memories.lock().ok()?.get(key)?.summary.clone()
+    }
+
+    /// Replaces the session's memory with an update's result, unless the memory was reset since the update started.
+    /// Returns whether the memory was replaced.
+    pub(crate) fn set_memory(&self, key: &SessionKey, update: &MemoryUpdate, summary: String) -> bool {
+        let Ok(mut memories) = self.memories.lock() else {
+            return false;
+        };
+
+        match memories.get_mut(key) {
+            Some(memory) if memory.generation == update.generation => {
+                memory.summary = Some(summary);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Forgets what's happened in a session's game, so that its memory starts over.
+    /// Returns whether the session had a memory.
+    pub(crate) fn reset_memory(&self, key: &SessionKey) -> bool {
+        let Ok(mut memories) = self.memories.lock() else {
+            return false;
+        };
+
+        match memories.get_mut(key) {
+            Some(memory) => {
+                memory.summary = None;
+                memory.generation += 1;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Every live session, most recently active first.
+    pub(crate) fn list(&self) -> Vec<SessionSummary> {
+        let Ok(mut sessions) = self.sessions.lock() else {
+            return vec![];
+        };
+        self.forget_stale(&mut sessions);
+
+        let mut summaries = sessions
+            .iter()
+            .map(|(key, session)| SessionSummary {
+                key: key.clone(),
+                narration: session.narration.clone(),
+                changes: session.changes.iter().cloned().collect(),
+                memory: self.memory(key),
+                frames: session.frames.len(),
+                idle: session.last_seen.elapsed().as_secs(),
+            })
+            .collect::<Vec<_>>();
+        summaries.sort_unstable_by_key(|summary| summary.idle);
+        summaries
+    }
+
+    /// Remembers the session's latest screenshot, and its narration if there was a new one.
+    /// When only changes are narrated, every narration after the first describes only what changed,
+    /// so those are kept apart from the full description that they build on.
+    pub(crate) fn remember(&self, key: &SessionKey, screenshot: u64, narration: Option<&str>) {
+        let changes_only = self.options.changes_only;
+        self.update(key, |session| {
+            session.screenshot = Some(screenshot);
+            let Some(narration) = narration else {
+                return;
+            };
+
+            if changes_only && session.narration.is_some() {
+                session.changes.push_back(narration.to_string());
+                while session.changes.len() > MAX_CHANGES {
+                    session.changes.pop_front();
+                }
+            } else {
+                session.narration = Some(narration.to_string());
+                session.changes.clear();
+            }
+        });
+    }
+
+    /// Adds a screenshot to those shown to the model with the session's next requests,
+    /// forgetting the oldest once there are enough.
+    /// Screenshots are shrunk in the background, so they may arrive out of order; they're kept in order of their calls.
+    pub(crate) fn push_frame(&self, key: &SessionKey, frame: Frame) {
+        let max = self.options.context_frames;
+        self.update(key, |session| {
+            let position = session.frames.partition_point(|kept| kept.id < frame.id);
+            session.frames.insert(position, frame);
+            while session.frames.len() > max {
+                session.frames.pop_front();
+            }
+        });
+    }
+
+    fn update(&self, key: &SessionKey, update: impl FnOnce(&mut Session)) {
+        let Ok(mut sessions) = self.sessions.lock() else {
+            return;
+        };
+
+        let now = Instant::now();
+        let session = sessions.entry(key.clone()).or_insert_with(|| Session {
+            screenshot: None,
+            narration: None,
+            changes: VecDeque::new(),
+            frames: VecDeque::new(),
+            last_seen: now,
+        });
+        session.last_seen = now;
+        update(session);
+    }
+
+    fn forget_stale(&self, sessions: &mut HashMap<SessionKey, Session>) {
+        let timeout = self.options.timeout;
+        sessions.retain(|_, session| session.last_seen.elapsed() < timeout);
+    }
+}
+
+impl MemoryUpdate {
+    /// Waits for the session's earlier updates to finish; the next one waits until the returned guard is dropped.
+    pub(crate) async fn turn(&self) -> MutexGuard<'_, ()> {
+        self.lock.lock().await
+    }
+}
+
+/// A hash of a base64-encoded screenshot's decoded contents, for telling whether two screenshots are the same
+/// without keeping a copy of either.
+pub(crate) fn screenshot_hash(image: &str) -> u64 {
+    match BASE64_STANDARD.decode(image) {
+        Ok(decoded) => feedback::image_hash(&decoded),
+        Err(_) => feedback::image_hash(image.as_bytes()),
+    }
+}
+
+/// Shrinks a base64-encoded screenshot to fit within `max_size` pixels, re-encoding it as PNG.
+/// Screenshots that already fit are only re-encoded.
+pub(crate) fn shrink(image: &str, max_size: u32) -> Result<String, String> {
+    let image = BASE64_STANDARD.decode(image).map_err(|e| e.to_string())?;
+    let mut image = image::load_from_memory(&image).map_err(|e| e.to_string())?;
+    if image.width() > max_size || image.height() > max_size {
+        image = image.thumbnail(max_size, max_size);
+    }
+
+    let mut cursor = Cursor::new(Vec::new());
+    image.write_to(&mut cursor, ImageFormat::Png).map_err(|e| e.to_string())?;
+    Ok(BASE64_STANDARD.encode(cursor.into_inner()))
+}
+
+/// Whether the model's answer (or one of its sentences) says that nothing changed.
+pub(crate) fn is_no_change(text: &str) -> bool {
+    text.trim().trim_end_matches('.').eq_ignore_ascii_case(NO_CHANGE)
+}
@@ -0,0 +1,22 @@
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Counts the messages that the AI service and web console had to give up on.
+/// None of these failures affect the player; they only mean the console's history is incomplete.
+#[derive(Debug, Default, Serialize)]
+pub(crate) struct PipelineStats {
+    /// Messages the AI service couldn't hand over to the web console because it had shut down.
+    pub(crate) unsent_messages: AtomicU64,
+    /// Messages the AI service dropped because the web console's queue was full.
+    pub(crate) dropped_messages: AtomicU64,
+    /// Messages the web console received for a call it has no record of.
+    pub(crate) orphaned_messages: AtomicU64,
+    /// Messages the web console received, but couldn't make sense of.
+    pub(crate) malformed_messages: AtomicU64,
+}
+
+impl PipelineStats {
+    pub(crate) fn count(counter: &AtomicU64) -> u64 {
+        counter.fetch_add(1, Ordering::Relaxed) + 1
+    }
+}
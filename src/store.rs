@@ -0,0 +1,145 @@
+use image::ImageFormat;
+use std::io;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::fs;
+
+const CALL_FILE: &str = "call.json";
+const REQUEST_IMAGE_STEM: &str = "request";
+const RESPONSE_IMAGE_STEM: &str = "response";
+const RESPONSE_SOUND_FILE: &str = "response.wav";
+const THUMBNAIL_FILE: &str = "thumbnail.png";
+
+/// An on-disk copy of the web console's history, so that it survives restarts.
+/// Each service call gets its own directory named after its ID,
+/// which holds the call as JSON alongside the screenshot and any spoken response.
+pub(crate) struct HistoryStore {
+    root: PathBuf,
+    /// Numbers temporary files, so that two writes of the same file can't write over each other's.
+    next_temporary: AtomicU64,
+}
+
+impl HistoryStore {
+    pub(crate) async fn open<P>(root: P) -> io::Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let root = root.as_ref().to_path_buf();
+        fs::create_dir_all(&root).await?;
+        log::info!(target: "groan", "Persisting history to {}", root.display());
+
+        Ok(Self { root, next_temporary: AtomicU64::new(0) })
+    }
+
+    /// IDs of every stored call, in ascending order.
+    pub(crate) async fn ids(&self) -> io::Result<Vec<u64>> {
+        let mut ids = vec![];
+        let mut entries = fs::read_dir(&self.root).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            if let Some(id) = entry.file_name().to_str().and_then(|name| name.parse::<u64>().ok()) {
+                ids.push(id);
+            }
+        }
+
+        ids.sort_unstable();
+        Ok(ids)
+    }
+
+    /// The ID that the next service call should use so that it doesn't overwrite anything stored.
+    pub(crate) async fn next_id(&self) -> io::Result<u64> {
+        Ok(self.ids().await?.last().map_or(0, |id| id + 1))
+    }
+
+    /// Saves a call that's already been serialized,
+    /// so that the caller doesn't need to hold onto the call while it's written.
+    pub(crate) async fn save_call(&self, id: u64, json: &[u8]) -> io::Result<()> {
+        self.write(id, CALL_FILE, json).await
+    }
+
+    pub(crate) async fn save_image(&self, id: u64, image: &[u8]) -> io::Result<()> {
+        self.write_image(id, REQUEST_IMAGE_STEM, image).await
+    }
+
+    pub(crate) async fn save_response_image(&self, id: u64, image: &[u8]) -> io::Result<()> {
+        self.write_image(id, RESPONSE_IMAGE_STEM, image).await
+    }
+
+    pub(crate) async fn save_thumbnail(&self, id: u64, thumbnail: &[u8]) -> io::Result<()> {
+        self.write(id, THUMBNAIL_FILE, thumbnail).await
+    }
+
+    pub(crate) async fn save_sound(&self, id: u64, sound: &[u8]) -> io::Result<()> {
+        self.write(id, RESPONSE_SOUND_FILE, sound).await
+    }
+
+    /// The stored call, as the JSON it was serialized to.
+    pub(crate) async fn load_call(&self, id: u64) -> io::Result<Option<Vec<u8>>> {
+        self.read(id, CALL_FILE).await
+    }
+
+    pub(crate) async fn load_image(&self, id: u64) -> io::Result<Option<Vec<u8>>> {
+        self.read_image(id, REQUEST_IMAGE_STEM).await
+    }
+
+    pub(crate) async fn load_response_image(&self, id: u64) -> io::Result<Option<Vec<u8>>> {
+        self.read_image(id, RESPONSE_IMAGE_STEM).await
+    }
+
+    pub(crate) async fn load_thumbnail(&self, id: u64) -> io::Result<Option<Vec<u8>>> {
+        self.read(id, THUMBNAIL_FILE).await
+    }
+
+    pub(crate) async fn load_sound(&self, id: u64) -> io::Result<Option<Vec<u8>>> {
+        self.read(id, RESPONSE_SOUND_FILE).await
+    }
+
+    /// Images are saved with the extension of whatever format they're in, so they open properly outside groan.
+    async fn write_image(&self, id: u64, stem: &str, image: &[u8]) -> io::Result<()> {
+        self.write(id, &format!("{}.{}", stem, image_extension(image)), image).await
+    }
+
+    async fn read_image(&self, id: u64, stem: &str) -> io::Result<Option<Vec<u8>>> {
+        for format in [ImageFormat::Png, ImageFormat::Bmp] {
+            for extension in format.extensions_str() {
+                if let Some(image) = self.read(id, &format!("{}.{}", stem, extension)).await? {
+                    return Ok(Some(image));
+                }
+            }
+        }
+
+        self.read(id, &format!("{}.bin", stem)).await
+    }
+
+    async fn write(&self, id: u64, name: &str, contents: &[u8]) -> io::Result<()> {
+        let directory = self.root.join(id.to_string());
+        fs::create_dir_all(&directory).await?;
+
+        // Write to a temporary file first so that a crash can't leave a truncated file behind
+        let number = self.next_temporary.fetch_add(1, Ordering::Relaxed);
+        let temporary = directory.join(format!("{}.{}.tmp", name, number));
+        fs::write(&temporary, contents).await?;
+        fs::rename(&temporary, directory.join(name)).await
+    }
+
+    async fn read(&self, id: u64, name: &str) -> io::Result<Option<Vec<u8>>> {
+        match fs::read(self.root.join(id.to_string()).join(name)).await {
+            Ok(contents) => Ok(Some(contents)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// The usual file extension for an encoded image, or `bin` if it isn't in a format groan knows.
+pub(crate) fn image_extension(image: &[u8]) -> &'static str {
+    image::guess_format(image)
+        .ok()
+        .and_then(|format| format.extensions_str().first().copied())
+        .unwrap_or("bin")
+}
+
+/// The MIME type of an encoded image, worked out from its contents rather than from what the client claimed.
+pub(crate) fn image_mime_type(image: &[u8]) -> &'static str {
+    image::guess_format(image).map_or("application/octet-stream", |format| format.to_mime_type())
+}
@@ -0,0 +1,572 @@
+/// Splits text that arrives a piece at a time into sentences as soon as each one is complete,
+/// so that they can be spoken while the rest is still being generated.
+#[derive(Debug, Default)]
+pub(crate) struct SentenceSplitter {
+    text: String,
+    /// How much of `text` has already been handed out as sentences.
+    split: usize,
+}
+
+impl SentenceSplitter {
+    /// Adds the next piece of text, returning any sentences that it completed.
+    pub(crate) fn push(&mut self, piece: &str) -> Vec<String> {
+        self.text.push_str(piece);
+
+        let mut sentences = vec![];
+        while let Some(end) = sentence_end(&self.text[self.split..]) {
+            let sentence = self.text[self.split..self.split + end].trim();
+            if !sentence.is_empty() {
+                sentences.push(sentence.to_string());
+            }
+            self.split += end;
+        }
+
+        sentences
+    }
+
+    /// Returns whatever's left over once there's no more text coming, along with all of the text.
+    pub(crate) fn finish(self) -> (Option<String>, String) {
+        let rest = self.text[self.split..].trim();
+        let rest = (!rest.is_empty()).then(|| rest.to_string());
+        (rest, self.text)
+    }
+}
+
+/// Abbreviations that are usually followed by more of the same sentence, in lowercase.
+/// Ones like "etc." that often end a sentence are left out; splitting too late only delays speech a little.
+const ABBREVIATIONS: &[&str] = &[
+    "e.g.", "i.e.", "vs.", "mr.", "mrs.", "ms.", "dr.", "st.", "jr.", "sr.", "lv.", "no.", "vol.", "ch.", "approx.",
+];
+
+/// Where the first complete sentence in `text` ends, if there is one.
+/// Punctuation only ends a sentence once the whitespace after it has arrived,
+/// so that e.g. "3.5" or "..." aren't split before the rest of them comes in.
+fn sentence_end(text: &str) -> Option<usize> {
+    let mut chars = text.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\n' => return Some(i + c.len_utf8()),
+            // These aren't followed by spaces in the languages that use them
+            '。' | '！' | '？' => return Some(i + c.len_utf8()),
+            '.' | '!' | '?' => {
+                if let Some(&(next, following)) = chars.peek() {
+                    if following.is_whitespace() && !(c == '.' && is_abbreviation(&text[..next])) {
+                        return Some(next);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    None
+}
+
+/// Whether the full stop at the end of `text` is part of an abbreviation, an initial or a number
+/// (e.g. "Lv. 5", "J. Doe" or "1. Start") rather than the end of a sentence.
+fn is_abbreviation(text: &str) -> bool {
+    let word = text.rsplit(char::is_whitespace).next().unwrap_or(text);
+    let word = word.trim_start_matches(['(', '"', '\'']);
+    let stem = word.strip_suffix('.').unwrap_or(word);
+
+    let initial = stem.chars().count() == 1 && stem.chars().all(char::is_alphabetic);
+    let number = !stem.is_empty() && stem.chars().all(|c| c.is_ascii_digit());
+    initial || number || ABBREVIATIONS.iter().any(|abbreviation| abbreviation.eq_ignore_ascii_case(word))
+}
+
+/// How model output is cleaned up before it's shown or spoken.
+/// Every step can be turned off, and the limits are optional.
+#[derive(Debug, Clone)]
+pub(crate) struct TextOptions {
+    /// Remove markdown formatting (headings, list markers, emphasis, code and links), keeping the words.
+    pub(crate) strip_markdown: bool,
+    pub(crate) strip_emoji: bool,
+    /// Turn every run of whitespace, including line breaks, into a single space.
+    pub(crate) collapse_whitespace: bool,
+    pub(crate) max_sentences: Option<usize>,
+    pub(crate) max_chars: Option<usize>,
+    /// Break on-screen text into lines of at most this many characters.
+    pub(crate) wrap_width: Option<usize>,
+    /// Abbreviations and what to say instead, for spoken text only.
+    pub(crate) abbreviations: Vec<(String, String)>,
+}
+
+/// Spelled-out forms of abbreviations that text-to-speech tends to read out letter by letter, or not at all.
+pub(crate) const DEFAULT_ABBREVIATIONS: &[(&str, &str)] = &[
+    ("e.g.", "for example"),
+    ("i.e.", "that is"),
+    ("etc.", "et cetera"),
+    ("vs.", "versus"),
+    ("HP", "hit points"),
+    ("MP", "magic points"),
+    ("XP", "experience points"),
+    ("EXP", "experience points"),
+    ("Lv.", "level"),
+    ("NPC", "N P C"),
+];
+
+/// Keeps track of how much of the length limits a response has used, as it's cleaned a sentence at a time.
+#[derive(Debug)]
+pub(crate) struct Limiter<'a> {
+    options: &'a TextOptions,
+    sentences: usize,
+    chars: usize,
+    /// Set once a sentence has been cut short, since nothing can fit after it.
+    full: bool,
+}
+
+impl TextOptions {
+    /// Cleans a whole response: strips what it's been told to, then cuts it down to the limits.
+    pub(crate) fn clean(&self, text: &str) -> String {
+        let mut limiter = self.limiter();
+        let mut splitter = SentenceSplitter::default();
+        let mut sentences = splitter.push(text);
+        sentences.extend(splitter.finish().0);
+
+        let sentences = sentences
+            .iter()
+            .map_while(|sentence| limiter.take(sentence))
+            .filter(|sentence| !sentence.is_empty())
+            .collect::<Vec<_>>();
+        self.join(&sentences)
+    }
+
+    /// Puts cleaned sentences back together.
+    /// Line breaks are kept between them unless whitespace is being collapsed.
+    pub(crate) fn join(&self, sentences: &[String]) -> String {
+        let separator = if self.collapse_whitespace { " " } else { "\n" };
+        sentences.join(separator)
+    }
+
+    pub(crate) fn limiter(&self) -> Limiter<'_> {
+        Limiter { options: self, sentences: 0, chars: 0, full: false }
+    }
+
+    /// Strips formatting from a piece of text without applying any limits.
+    fn strip(&self, text: &str) -> String {
+        let mut text = text.to_string();
+        if self.strip_markdown {
+            text = strip_markdown(&text);
+        }
+        if self.strip_emoji {
+            text = text.chars().filter(|&c| !is_emoji(c)).collect();
+        }
+        if self.collapse_whitespace {
+            text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+        }
+        text.trim().to_string()
+    }
+
+    /// Prepares cleaned text for RetroArch's on-screen display.
+    pub(crate) fn for_display(&self, text: &str) -> String {
+        match self.wrap_width {
+            Some(width) => text.lines().map(|line| wrap(line, width)).collect::<Vec<_>>().join("\n"),
+            None => text.to_string(),
+        }
+    }
+
+    /// Prepares cleaned text for text-to-speech.
+    pub(crate) fn for_speech(&self, text: &str) -> String {
+        self.abbreviations
+            .iter()
+            .fold(text.to_string(), |text, (abbreviation, expansion)| replace_word(&text, abbreviation, expansion))
+    }
+}
+
+impl Limiter<'_> {
+    /// Cleans the next sentence, cutting it short if it would go over the character limit.
+    /// Returns `None` once the limits have been reached, after which nothing more should be shown or spoken.
+    pub(crate) fn take(&mut self, sentence: &str) -> Option<String> {
+        if self.full || self.options.max_sentences.is_some_and(|max| self.sentences >= max) {
+            return None;
+        }
+
+        let sentence = self.options.strip(sentence);
+        if sentence.is_empty() {
+            return Some(sentence); // e.g. a line that was only a code fence; it doesn't count
+        }
+
+        // Count the space that will join this sentence to the last one
+        let separator = usize::from(self.chars > 0);
+        let length = sentence.chars().count();
+        let sentence = match self.options.max_chars {
+            Some(max) if self.chars + separator + length > max => {
+                let room = max.saturating_sub(self.chars + separator);
+                let truncated = truncate_at_word(&sentence, room);
+                self.full = true;
+                if truncated.is_empty() {
+                    return None;
+                }
+                truncated
+            }
+            _ => {
+                self.chars += separator + length;
+                self.sentences += 1;
+                sentence
+            }
+        };
+
+        Some(sentence)
+    }
+}
+
+/// Parses an abbreviation and its spoken form, given as `FROM=TO`.
+pub(crate) fn parse_abbreviation(abbreviation: &str) -> Result<(String, String), String> {
+    match abbreviation.split_once('=') {
+        Some((from, to)) if !from.trim().is_empty() => Ok((from.trim().to_string(), to.trim().to_string())),
+        _ => Err(format!("\"{}\" should look like FROM=TO", abbreviation)),
+    }
+}
+
+/// Removes markdown syntax line by line, since the model's markdown rarely spans lines in ways that matter for reading aloud.
+fn strip_markdown(text: &str) -> String {
+    text.lines()
+        .filter(|line| !line.trim_start().starts_with("```"))
+        .map(|line| strip_inline_markdown(strip_line_marker(line.trim_start())))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Removes a heading, quote or list marker from the start of a line.
+/// Markers only count with a space after them, so that e.g. "#1 rank" or ">9000" are kept.
+fn strip_line_marker(line: &str) -> &str {
+    let mut trimmed = line.trim_start();
+    let hashes = trimmed.len() - trimmed.trim_start_matches('#').len();
+    if hashes > 0 && trimmed[hashes..].starts_with(' ') {
+        trimmed = trimmed[hashes..].trim_start();
+    }
+    while let Some(rest) = trimmed.strip_prefix('>').filter(|rest| rest.starts_with(' ')) {
+        trimmed = rest.trim_start();
+    }
+
+    if let Some(rest) = trimmed.strip_prefix(['-', '*', '+']).filter(|rest| rest.starts_with(' ')) {
+        return rest.trim_start();
+    }
+
+    let digits = trimmed.chars().take_while(char::is_ascii_digit).count();
+    if digits > 0 {
+        if let Some(rest) = trimmed[digits..].strip_prefix(['.', ')']).filter(|rest| rest.starts_with(' ')) {
+            return rest.trim_start();
+        }
+    }
+
+    trimmed
+}
+
+/// Removes emphasis and code markers, and replaces links and images with their text.
+/// Markers are only removed in pairs, so that e.g. "2 * 3" or a lone backtick are kept.
+fn strip_inline_markdown(line: &str) -> String {
+    let mut stripped = String::with_capacity(line.len());
+    let mut open = vec![];
+    let mut rest = line;
+    while let Some(c) = rest.chars().next() {
+        // [text](url) and ![text](url) become text
+        let link = rest.strip_prefix('!').unwrap_or(rest);
+        if let Some((text, after)) = link.strip_prefix('[').and_then(|link| link.split_once("](")) {
+            if let Some((_, after)) = after.split_once(')') {
+                stripped.push_str(&strip_inline_markdown(text));
+                rest = after;
+                continue;
+            }
+        }
+
+        let Some(marker) = ["**", "__", "~~", "*", "`"].into_iter().find(|marker| rest.starts_with(marker)) else {
+            stripped.push(c);
+            rest = &rest[c.len_utf8()..];
+            continue;
+        };
+
+        let after = &rest[marker.len()..];
+        if open.contains(&marker) {
+            open.retain(|&opened| opened != marker);
+        } else if after.starts_with(|c: char| !c.is_whitespace()) && after.contains(marker) {
+            open.push(marker);
+        } else {
+            stripped.push_str(marker);
+        }
+        rest = after;
+    }
+
+    stripped
+}
+
+fn is_emoji(c: char) -> bool {
+    matches!(
+        u32::from(c),
+        0x1F000..=0x1FAFF // Pictographs, emoticons, transport, flags and the like
+            | 0x2600..=0x27BF // Miscellaneous symbols and dingbats
+            | 0x2B00..=0x2BFF // Arrows, stars and other symbols used as emoji
+            | 0xFE00..=0xFE0F // Variation selectors
+            | 0x200D // Zero-width joiner, which glues emoji sequences together
+            | 0xE0020..=0xE007F // Tags, used in subdivision flags
+    )
+}
+
+/// Cuts text down to at most `max` characters, preferring to end at a word boundary.
+fn truncate_at_word(text: &str, max: usize) -> String {
+    let Some((end, _)) = text.char_indices().nth(max) else {
+        return text.to_string();
+    };
+
+    let cut = &text[..end];
+    let cut = match cut.rfind(char::is_whitespace) {
+        Some(space) if !text[end..].starts_with(char::is_whitespace) => &cut[..space],
+        _ => cut,
+    };
+    cut.trim_end().to_string()
+}
+
+/// Greedily breaks a line into lines of at most `width` characters, splitting words only if they're longer than that.
+fn wrap(line: &str, width: usize) -> String {
+    let width = width.max(1);
+    let mut lines = vec![];
+    let mut current = String::new();
+    let mut current_length = 0;
+    for word in line.split_whitespace() {
+        let mut word = word.to_string();
+        let mut length = word.chars().count();
+
+        if current_length > 0 && current_length + 1 + length > width {
+            lines.push(std::mem::take(&mut current));
+            current_length = 0;
+        }
+        while length > width {
+            let (end, _) = word.char_indices().nth(width).unwrap_or((word.len(), ' '));
+            lines.push(word[..end].to_string());
+            word = word[end..].to_string();
+            length -= width;
+        }
+
+        if current_length > 0 {
+            current.push(' ');
+            current_length += 1;
+        }
+        current.push_str(&word);
+        current_length += length;
+    }
+
+    if !current.is_empty() || lines.is_empty() {
+        lines.push(current);
+    }
+    lines.join("\n")
+}
+
+/// Replaces whole-word occurrences of `word`, so that e.g. "HP" doesn't change "HPS" or "SHP".
+fn replace_word(text: &str, word: &str, replacement: &str) -> String {
+    if word.is_empty() {
+        return text.to_string();
+    }
+
+    let mut replaced = String::with_capacity(text.len());
+    let mut last = 0;
+    for (start, _) in text.match_indices(word) {
+        let end = start + word.len();
+        let before = text[..start].chars().next_back();
+        let after = text[end..].chars().next();
+        let bounded = |c: Option<char>| !matches!(c, Some(c) if c.is_alphanumeric());
+        if bounded(before) && bounded(after) {
+            replaced.push_str(&text[last..start]);
+            replaced.push_str(replacement);
+            last = end;
+        }
+    }
+
+    replaced.push_str(&text[last..]);
+    replaced
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feeds the text to a splitter in pieces of the given size, as a stream would, and collects every sentence.
+    fn split(text: &str, piece_size: usize) -> Vec<String> {
+        let mut splitter = SentenceSplitter::default();
+        let chars = text.chars().collect::<Vec<_>>();
+        let mut sentences = vec![];
+        for piece in chars.chunks(piece_size) {
+            sentences.extend(splitter.push(&piece.iter().collect::<String>()));
+        }
+        sentences.extend(splitter.finish().0);
+        sentences
+    }
+
+    #[test]
+    fn splits_sentences() {
+        let cases: &[(&str, &[&str])] = &[
+            ("One. Two! Three? Four", &["One.", "Two!", "Three?", "Four"]),
+            ("A line\nAnother line", &["A line", "Another line"]),
+            ("日本語です。次の文！", &["日本語です。", "次の文！"]),
+            ("It costs 3.5 gold. Buy it.", &["It costs 3.5 gold.", "Buy it."]),
+            ("Wait... Go on.", &["Wait...", "Go on."]),
+            ("  ", &[]),
+        ];
+
+        for (text, expected) in cases {
+            for piece_size in [1, 3, text.len().max(1)] {
+                assert_eq!(split(text, piece_size), *expected, "{:?} in pieces of {}", text, piece_size);
+            }
+        }
+    }
+
+    #[test]
+    fn keeps_abbreviations_and_numbers_in_their_sentence() {
+        let cases: &[(&str, &[&str])] = &[
+            ("Use items, e.g. potions. Then rest.", &["Use items, e.g. potions.", "Then rest."]),
+            ("Mr. Toad waves. He leaves.", &["Mr. Toad waves.", "He leaves."]),
+            ("Link is Lv. 5 now. Onward.", &["Link is Lv. 5 now.", "Onward."]),
+            ("1. Start the game", &["1. Start the game"]),
+            ("Written by J. Doe. The end.", &["Written by J. Doe.", "The end."]),
+            ("(Vol. 2) is out. Read it.", &["(Vol. 2) is out.", "Read it."]),
+        ];
+
+        for (text, expected) in cases {
+            assert_eq!(split(text, 1), *expected, "{:?}", text);
+        }
+    }
+
+    /// Options with every step turned off, for turning on one at a time.
+    fn plain() -> TextOptions {
+        TextOptions {
+            strip_markdown: false,
+            strip_emoji: false,
+            collapse_whitespace: false,
+            max_sentences: None,
+            max_chars: None,
+            wrap_width: None,
+            abbreviations: vec![],
+        }
+    }
+
+    #[test]
+    fn strips_line_markers() {
+        let cases = [
+            ("# Title", "Title"),
+            ("### Deeper title", "Deeper title"),
+            ("> Quoted", "Quoted"),
+            ("> > Nested quote", "Nested quote"),
+            ("- Item", "Item"),
+            ("* Item", "Item"),
+            ("+ Item", "Item"),
+            ("1. First", "First"),
+            ("12) Twelfth", "Twelfth"),
+            ("   - Indented", "Indented"),
+            ("#1 rank", "#1 rank"),
+            (">9000 power", ">9000 power"),
+            ("-5 HP", "-5 HP"),
+            ("3.5 seconds", "3.5 seconds"),
+        ];
+
+        for (line, expected) in cases {
+            assert_eq!(strip_line_marker(line), expected, "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn strips_inline_markers_in_pairs() {
+        let cases = [
+            ("**bold** and __bold__", "bold and bold"),
+            ("*italic* and ~~struck~~", "italic and struck"),
+            ("Press `Start`", "Press Start"),
+            ("***both***", "both"),
+            ("2 * 3 = 6", "2 * 3 = 6"),
+            ("a * b * c", "a * b * c"),
+            ("A lone ` backtick", "A lone ` backtick"),
+            ("*unclosed emphasis", "*unclosed emphasis"),
+            ("See [the map](https://example.com) or ![a chest](chest.png)", "See the map or a chest"),
+            ("[not a link] (really)", "[not a link] (really)"),
+        ];
+
+        for (line, expected) in cases {
+            assert_eq!(strip_inline_markdown(line), expected, "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn cleans_with_each_flag() {
+        let text = "## A **cave** 🦇\n\n```\ncode\n```\nIt's   dark.";
+        let cases = [
+            // Blank lines are never kept
+            (plain(), "## A **cave** 🦇\n```\ncode\n```\nIt's   dark."),
+            (TextOptions { strip_markdown: true, ..plain() }, "A cave 🦇\ncode\nIt's   dark."),
+            (TextOptions { strip_emoji: true, ..plain() }, "## A **cave**\n```\ncode\n```\nIt's   dark."),
+            (TextOptions { collapse_whitespace: true, ..plain() }, "## A **cave** 🦇 ``` code ``` It's dark."),
+            (
+                TextOptions { strip_markdown: true, strip_emoji: true, collapse_whitespace: true, ..plain() },
+                "A cave code It's dark.",
+            ),
+        ];
+
+        for (options, expected) in cases {
+            assert_eq!(options.clean(text), expected, "{:?}", options);
+        }
+    }
+
+    #[test]
+    fn cleans_within_the_limits() {
+        let text = "One two three. Four five six. Seven.";
+        let cases = [
+            (None, None, "One two three.\nFour five six.\nSeven."),
+            (Some(2), None, "One two three.\nFour five six."),
+            (None, Some(14), "One two three."),
+            (None, Some(20), "One two three.\nFour"),
+            (None, Some(7), "One two"),
+            (Some(1), Some(100), "One two three."),
+        ];
+
+        for (max_sentences, max_chars, expected) in cases {
+            let options = TextOptions { max_sentences, max_chars, ..plain() };
+            assert_eq!(options.clean(text), expected, "{:?} sentences, {:?} characters", max_sentences, max_chars);
+        }
+    }
+
+    #[test]
+    fn wraps_for_display() {
+        let cases = [
+            (None, "A short line\nand another", "A short line\nand another"),
+            (Some(10), "The quick brown fox jumps", "The quick\nbrown fox\njumps"),
+            (Some(4), "Unbreakable", "Unbr\neaka\nble"),
+            (Some(6), "One two\nthree", "One\ntwo\nthree"),
+        ];
+
+        for (wrap_width, text, expected) in cases {
+            assert_eq!(TextOptions { wrap_width, ..plain() }.for_display(text), expected, "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn expands_abbreviations_for_speech() {
+        let abbreviations = DEFAULT_ABBREVIATIONS.iter().map(|(from, to)| (from.to_string(), to.to_string())).collect();
+        let options = TextOptions { abbreviations, ..plain() };
+        let cases = [
+            ("Restore HP and MP.", "Restore hit points and magic points."),
+            ("Lv. 5, e.g. a slime", "level 5, for example a slime"),
+            ("HPS and SHP stay", "HPS and SHP stay"),
+            ("Talk to the NPC", "Talk to the N P C"),
+        ];
+
+        for (text, expected) in cases {
+            assert_eq!(options.for_speech(text), expected, "{:?}", text);
+        }
+        assert_eq!(plain().for_speech("HP"), "HP");
+    }
+
+    #[test]
+    fn parses_abbreviations() {
+        assert_eq!(parse_abbreviation(" HP = hit points "), Ok(("HP".to_string(), "hit points".to_string())));
+        assert_eq!(parse_abbreviation("KO="), Ok(("KO".to_string(), String::new())));
+        assert!(parse_abbreviation("=nothing").is_err());
+        assert!(parse_abbreviation("no equals sign").is_err());
+    }
+
+    #[test]
+    fn finish_returns_the_rest_and_all_text() {
+        let mut splitter = SentenceSplitter::default();
+        assert_eq!(splitter.push("Done. Not done"), vec!["Done."]);
+        assert_eq!(splitter.finish(), (Some("Not done".to_string()), "Done. Not done".to_string()));
+
+        let mut splitter = SentenceSplitter::default();
+        assert_eq!(splitter.push("Done. "), vec!["Done."]);
+        assert_eq!(splitter.finish(), (None, "Done. ".to_string()));
+    }
+}
@@ -0,0 +1,235 @@
+use async_openai::types::Voice;
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+/// espeak-ng's default speaking rate, in words per minute.
+const ESPEAK_DEFAULT_RATE: f32 = 175.0;
+
+/// Which engine turns text into speech.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum SpeechBackend {
+    /// OpenAI's text-to-speech API.
+    Openai,
+    /// espeak-ng, run on this machine; it works offline and costs nothing, but sounds robotic.
+    Espeak,
+}
+
+/// How spoken responses are generated.
+#[derive(Debug, Clone)]
+pub(crate) struct SpeechOptions {
+    pub(crate) voices: VoiceTable,
+    /// Speak with espeak-ng instead if OpenAI's text-to-speech fails.
+    pub(crate) fallback: bool,
+    /// How many text-to-speech requests may run at once, across every client.
+    pub(crate) concurrency: usize,
+    pub(crate) espeak: Espeak,
+}
+
+/// Who speaks a response, and how fast.
+#[derive(Debug, Clone)]
+pub(crate) struct VoiceSettings {
+    pub(crate) backend: SpeechBackend,
+    /// The voice's name as the backend knows it, e.g. `fable` for OpenAI or `de` for espeak-ng.
+    /// If not given, OpenAI uses `fable` and espeak-ng uses `fallback_voice`.
+    pub(crate) voice: Option<String>,
+    /// A multiple of the backend's normal speaking rate.
+    pub(crate) speed: f32,
+    /// The espeak-ng voice to speak with if `voice` doesn't name one for espeak-ng,
+    /// either because it isn't given or because OpenAI's text-to-speech failed.
+    /// It's the response's language, since espeak-ng picks voices by language code;
+    /// if not given, espeak-ng uses --espeak-voice or its own default.
+    pub(crate) fallback_voice: Option<String>,
+}
+
+/// An entry in the voice table's file; anything it leaves out comes from the default settings.
+#[derive(Debug, Clone, Deserialize)]
+struct VoiceEntry {
+    backend: Option<SpeechBackend>,
+    voice: Option<String>,
+    speed: Option<f32>,
+}
+
+/// Voice settings for each language that RetroArch might ask for responses in, plus a default for the rest.
+#[derive(Debug, Clone)]
+pub(crate) struct VoiceTable {
+    default: VoiceSettings,
+    /// Keyed by lowercase language code, e.g. `ja` or `pt-br`.
+    languages: HashMap<String, VoiceSettings>,
+}
+
+impl VoiceSettings {
+    pub(crate) fn new(backend: SpeechBackend, speed: f32) -> Self {
+        Self { backend, voice: None, speed, fallback_voice: None }
+    }
+
+    /// The OpenAI voice to use, if this names one.
+    pub(crate) fn openai_voice(&self) -> Result<Option<Voice>, String> {
+        let Some(voice) = &self.voice else {
+            return Ok(None);
+        };
+
+        serde_json::from_value(Value::String(voice.to_lowercase()))
+            .map(Some)
+            .map_err(|_| format!("\"{}\" isn't one of OpenAI's voices", voice))
+    }
+
+    /// Makes sure that the backend can use these settings, naming the language they're for if it can't.
+    fn check(&self, language: &str) -> Result<(), String> {
+        if self.speed.is_nan() || self.speed <= 0.0 {
+            return Err(format!("The speed for \"{}\" must be more than 0", language));
+        }
+        if self.backend == SpeechBackend::Openai {
+            if !(0.25..=4.0).contains(&self.speed) {
+                return Err(format!("The speed for \"{}\" must be between 0.25 and 4 for OpenAI", language));
+            }
+            self.openai_voice().map_err(|e| format!("The voice for \"{}\" is wrong: {}", language, e))?;
+        }
+        Ok(())
+    }
+}
+
+impl VoiceTable {
+    /// A table that speaks every language with the same settings.
+    pub(crate) fn new(default: VoiceSettings) -> Result<Self, String> {
+        default.check("default")?;
+        Ok(Self { default, languages: HashMap::new() })
+    }
+
+    /// Loads a JSON object that maps language codes to voice settings, e.g.
+    /// `{"ja": {"backend": "espeak", "voice": "ja"}, "default": {"voice": "nova", "speed": 1.2}}`.
+    /// A `default` entry overrides the given default, and the other entries take the backend and speed
+    /// from the result if they leave them out.
+    pub(crate) async fn load(path: &Path, default: VoiceSettings) -> Result<Self, String> {
+        let json = tokio::fs::read(path).await.map_err(|e| format!("Couldn't read {}: {}", path.display(), e))?;
+        let mut entries = serde_json::from_slice::<HashMap<String, VoiceEntry>>(&json)
+            .map_err(|e| format!("Couldn't parse {}: {}", path.display(), e))?
+            .into_iter()
+            .map(|(language, entry)| (normalize_language(&language), entry))
+            .collect::<HashMap<_, _>>();
+
+        let default = match entries.remove("default") {
+            Some(entry) => entry.or(&default),
+            None => default,
+        };
+        let languages = entries
+            .into_iter()
+            .map(|(language, entry)| (language, entry.or(&default)))
+            .collect::<HashMap<_, _>>();
+
+        default.check("default")?;
+        for (language, settings) in &languages {
+            settings.check(language)?;
+        }
+
+        Ok(Self { default, languages })
+    }
+
+    /// Whether any language is spoken with the given backend.
+    pub(crate) fn uses(&self, backend: SpeechBackend) -> bool {
+        self.default.backend == backend || self.languages.values().any(|settings| settings.backend == backend)
+    }
+
+    /// The settings for a language code as RetroArch sends it (e.g. `ja` or `zh_CN`).
+    /// A region-specific code falls back to its language's entry, and anything else to the default.
+    pub(crate) fn for_language(&self, language: Option<&str>) -> VoiceSettings {
+        let Some(language) = language.map(normalize_language).filter(|language| !language.is_empty()) else {
+            return self.default.clone();
+        };
+
+        let settings = self
+            .languages
+            .get(&language)
+            .or_else(|| language.split_once('-').and_then(|(primary, _)| self.languages.get(primary)))
+            .unwrap_or(&self.default);
+        VoiceSettings { fallback_voice: Some(language), ..settings.clone() }
+    }
+}
+
+impl VoiceEntry {
+    /// Fills in the backend and speed from the given settings if the entry leaves them out.
+    fn or(self, default: &VoiceSettings) -> VoiceSettings {
+        VoiceSettings {
+            backend: self.backend.unwrap_or(default.backend),
+            voice: self.voice,
+            speed: self.speed.unwrap_or(default.speed),
+            fallback_voice: None,
+        }
+    }
+}
+
+fn normalize_language(language: &str) -> String {
+    language.trim().to_lowercase().replace('_', "-")
+}
+
+/// How to run espeak-ng.
+#[derive(Debug, Clone)]
+pub(crate) struct Espeak {
+    pub(crate) command: PathBuf,
+    /// The espeak-ng voice (e.g. `en-us`) to use when neither the voice table nor the response's language names one;
+    /// if not given, espeak-ng uses its own default.
+    pub(crate) voice: Option<String>,
+}
+
+impl Espeak {
+    /// Makes sure that espeak-ng can be run at all.
+    pub(crate) async fn check(&self) -> Result<(), String> {
+        let output = Command::new(&self.command)
+            .arg("--version")
+            .stdin(Stdio::null())
+            .output()
+            .await
+            .map_err(|e| format!("Couldn't run {}: {}", self.command.display(), e))?;
+
+        if !output.status.success() {
+            return Err(format!("{} --version exited with {}", self.command.display(), output.status));
+        }
+        Ok(())
+    }
+
+    /// Speaks the text at the given multiple of espeak-ng's normal rate, returning the WAV file it writes.
+    /// The text goes through standard input so that nothing in it can be mistaken for an option.
+    pub(crate) async fn speak(&self, text: &str, voice: Option<&str>, speed: f32) -> Result<Bytes, String> {
+        let rate = (ESPEAK_DEFAULT_RATE * speed).round() as u32;
+        let mut command = Command::new(&self.command);
+        command.args(["--stdout", "--stdin", "-s"]).arg(rate.to_string());
+        if let Some(voice) = voice.or(self.voice.as_deref()) {
+            command.arg("-v").arg(voice);
+        }
+
+        let mut child = command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| format!("Couldn't run {}: {}", self.command.display(), e))?;
+
+        let Some(mut stdin) = child.stdin.take() else {
+            return Err("espeak-ng's standard input wasn't captured".into());
+        };
+
+        // Write the text while reading the audio, so that neither side can fill its pipe and stall the other
+        let write = async move {
+            let written = stdin.write_all(text.as_bytes()).await;
+            drop(stdin); // Closing standard input tells espeak-ng that the text is over
+            written
+        };
+        let (written, output) = tokio::join!(write, child.wait_with_output());
+        let output = output.map_err(|e| format!("espeak-ng failed: {}", e))?;
+
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(format!("espeak-ng exited with {}: {}", output.status, stderr.trim()));
+        }
+        written.map_err(|e| format!("Couldn't give espeak-ng the text: {}", e))?;
+
+        Ok(Bytes::from(output.stdout))
+    }
+}
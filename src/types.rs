@@ -50,7 +50,7 @@ pub(crate) enum SoundOutputFormat {
     Wav,
 }
 
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, Clone, Copy)]
 #[serde(rename_all = "lowercase")]
 pub(crate) enum ImageOutputFormat {
     Bmp,
@@ -61,6 +61,8 @@ pub(crate) enum ImageOutputFormat {
 
 #[derive(Deserialize, Serialize)]
 pub(crate) struct RequestBody {
+    /// Left out when recording the body, since the web console keeps the decoded screenshot on its own.
+    #[serde(skip_serializing)]
     pub(crate) image: String,
     pub(crate) format: Option<ImageOutputFormat>,
     pub(crate) coords: Option<(i32, i32, i32, i32)>,
@@ -104,10 +106,13 @@ pub(crate) struct InputState {
     pub(crate) r3: u8,
 }
 
+/// Rejection for AI service requests whose query parameters or body couldn't be deserialized.
+/// Holds the deserializer's explanation, which names the offending field.
+/// The call has already recorded it as a `ServiceError::BadInput` by the time it's rejected.
 #[derive(Debug)]
-pub(crate) struct InvalidRequestBody;
+pub(crate) struct InvalidInput(pub(crate) String);
 
-impl warp::reject::Reject for InvalidRequestBody {}
+impl warp::reject::Reject for InvalidInput {}
 
 impl ResponseBody {
     pub(crate) fn text<T>(text: T) -> Self
@@ -0,0 +1,398 @@
+/// The parts of a WAV file that matter for putting several of them together.
+pub(crate) struct Wav<'a> {
+    /// The body of the `fmt ` chunk, which describes the samples.
+    pub(crate) format: &'a [u8],
+    /// The samples themselves.
+    pub(crate) data: &'a [u8],
+}
+
+/// Uncompressed 16-bit audio, which is what OpenAI's text-to-speech produces and what RetroArch plays most reliably.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Audio {
+    pub(crate) sample_rate: u32,
+    pub(crate) channels: u16,
+    /// Interleaved by channel.
+    pub(crate) samples: Vec<i16>,
+}
+
+/// How spoken responses should be converted before they're sent to RetroArch.
+/// Anything that's `None` is left as the text-to-speech backend made it.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct AudioOptions {
+    pub(crate) sample_rate: Option<u32>,
+    pub(crate) channels: Option<u16>,
+    /// The loudness (as RMS level, in dBFS) to bring every response to, so that they all sound about as loud.
+    pub(crate) loudness: Option<f32>,
+}
+
+const HEADER_SIZE: usize = 12;
+const CHUNK_HEADER_SIZE: usize = 8;
+const PCM_FORMAT_SIZE: usize = 16;
+const FORMAT_PCM: u16 = 1;
+const FORMAT_EXTENSIBLE: u16 = 0xFFFE;
+
+/// Finds the format and samples in a WAV file, skipping any other chunks.
+/// Sizes of -1 (as streaming encoders like OpenAI's write) or past the end of the file
+/// are taken to mean "until the end of the file".
+pub(crate) fn parse(wav: &[u8]) -> Result<Wav<'_>, String> {
+    if wav.len() < HEADER_SIZE || &wav[0..4] != b"RIFF" || &wav[8..12] != b"WAVE" {
+        return Err("not a RIFF/WAVE file".into());
+    }
+
+    let mut format = None;
+    let mut offset = HEADER_SIZE;
+    while offset + CHUNK_HEADER_SIZE <= wav.len() {
+        let id = &wav[offset..offset + 4];
+        let size = u32_at(wav, offset + 4);
+        let start = offset + CHUNK_HEADER_SIZE;
+        let end = match usize::try_from(size) {
+            Ok(size) if size != u32::MAX as usize && start + size <= wav.len() => start + size,
+            _ => wav.len(),
+        };
+
+        match id {
+            b"fmt " => format = Some(&wav[start..end]),
+            b"data" => {
+                let format = format.ok_or("the data chunk comes before the fmt chunk")?;
+                return Ok(Wav { format, data: &wav[start..end] });
+            }
+            _ => {} // Other chunks (e.g. LIST) aren't needed
+        }
+
+        // Chunks are padded to an even length
+        offset = end + (end - start) % 2;
+    }
+
+    Err("no data chunk".into())
+}
+
+/// Writes a WAV file with the given format and samples, with the RIFF and data sizes filled in correctly.
+pub(crate) fn write(format: &[u8], data: &[u8]) -> Vec<u8> {
+    let padding = data.len() % 2;
+    let riff_size = 4 + CHUNK_HEADER_SIZE + format.len() + CHUNK_HEADER_SIZE + data.len() + padding;
+
+    let mut wav = Vec::with_capacity(CHUNK_HEADER_SIZE + riff_size);
+    wav.extend_from_slice(b"RIFF");
+    wav.extend_from_slice(&(riff_size as u32).to_le_bytes());
+    wav.extend_from_slice(b"WAVE");
+    wav.extend_from_slice(b"fmt ");
+    wav.extend_from_slice(&(format.len() as u32).to_le_bytes());
+    wav.extend_from_slice(format);
+    wav.extend_from_slice(b"data");
+    wav.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    wav.extend_from_slice(data);
+    wav.resize(wav.len() + padding, 0);
+    wav
+}
+
+/// Joins WAV files end to end into one, converting them as the options say.
+/// Files that are already in the same format and don't need converting are joined without decoding them.
+pub(crate) fn join<T>(segments: &[T], options: &AudioOptions) -> Result<Vec<u8>, String>
+where
+    T: AsRef<[u8]>,
+{
+    let wavs = segments
+        .iter()
+        .enumerate()
+        .map(|(index, segment)| parse(segment.as_ref()).map_err(|e| format!("segment {}: {}", index, e)))
+        .collect::<Result<Vec<_>, _>>()?;
+    let first = wavs.first().ok_or("there's nothing to join")?;
+
+    let unconverted = options.sample_rate.is_none() && options.channels.is_none() && options.loudness.is_none();
+    if unconverted && wavs.iter().all(|wav| wav.format == first.format) {
+        let data = wavs.iter().flat_map(|wav| wav.data).copied().collect::<Vec<_>>();
+        return Ok(write(first.format, &data));
+    }
+
+    // Everything is converted to the first segment's format, after applying the options to it
+    let mut joined: Option<Audio> = None;
+    for (index, wav) in wavs.iter().enumerate() {
+        let audio = wav.audio().map_err(|e| format!("segment {}: {}", index, e))?;
+        joined = Some(match joined.take() {
+            None => {
+                let sample_rate = options.sample_rate.unwrap_or(audio.sample_rate);
+                let channels = options.channels.unwrap_or(audio.channels);
+                audio.with_channels(channels).resampled(sample_rate)
+            }
+            Some(mut joined) => {
+                let audio = audio.with_channels(joined.channels).resampled(joined.sample_rate);
+                joined.samples.extend(audio.samples);
+                joined
+            }
+        });
+    }
+
+    let mut joined = joined.ok_or("there's nothing to join")?;
+    if let Some(loudness) = options.loudness {
+        joined.normalize(loudness);
+    }
+
+    Ok(joined.to_wav())
+}
+
+impl Wav<'_> {
+    /// Decodes the samples, if they're 16-bit PCM.
+    pub(crate) fn audio(&self) -> Result<Audio, String> {
+        let format = self.format;
+        if format.len() < PCM_FORMAT_SIZE {
+            return Err("the fmt chunk is too short".into());
+        }
+
+        let tag = u16_at(format, 0);
+        // WAVE_FORMAT_EXTENSIBLE keeps the real format tag at the start of its sub-format GUID
+        let tag = match (tag, format.get(24..26)) {
+            (FORMAT_EXTENSIBLE, Some(sub_format)) => u16::from_le_bytes([sub_format[0], sub_format[1]]),
+            (tag, _) => tag,
+        };
+        let channels = u16_at(format, 2);
+        let sample_rate = u32_at(format, 4);
+        let bits = u16_at(format, 14);
+
+        if tag != FORMAT_PCM || bits != 16 {
+            return Err(format!("only 16-bit PCM can be converted, not format {} with {} bits", tag, bits));
+        }
+        if channels == 0 || sample_rate == 0 {
+            return Err("the fmt chunk has no channels or no sample rate".into());
+        }
+
+        let samples = self
+            .data
+            .chunks_exact(2)
+            .map(|sample| i16::from_le_bytes([sample[0], sample[1]]))
+            .collect();
+
+        Ok(Audio { sample_rate, channels, samples })
+    }
+}
+
+impl Audio {
+    pub(crate) fn to_wav(&self) -> Vec<u8> {
+        let block_align = self.channels * 2;
+        let mut format = Vec::with_capacity(PCM_FORMAT_SIZE);
+        format.extend_from_slice(&FORMAT_PCM.to_le_bytes());
+        format.extend_from_slice(&self.channels.to_le_bytes());
+        format.extend_from_slice(&self.sample_rate.to_le_bytes());
+        format.extend_from_slice(&(self.sample_rate * u32::from(block_align)).to_le_bytes());
+        format.extend_from_slice(&block_align.to_le_bytes());
+        format.extend_from_slice(&16u16.to_le_bytes());
+
+        let data = self.samples.iter().flat_map(|sample| sample.to_le_bytes()).collect::<Vec<_>>();
+        write(&format, &data)
+    }
+
+    /// Mixes down to mono by averaging, or spreads out from mono by copying.
+    /// Between two multichannel layouts, channels are kept or dropped in order.
+    pub(crate) fn with_channels(self, channels: u16) -> Self {
+        if channels == self.channels || channels == 0 {
+            return self;
+        }
+
+        let from = usize::from(self.channels);
+        let to = usize::from(channels);
+        let mut samples = Vec::with_capacity(self.samples.len() / from * to);
+        for frame in self.samples.chunks_exact(from) {
+            if to == 1 {
+                let sum = frame.iter().map(|&sample| i32::from(sample)).sum::<i32>();
+                samples.push((sum / from as i32) as i16);
+            } else {
+                samples.extend((0..to).map(|channel| frame[channel.min(from - 1)]));
+            }
+        }
+
+        Self { channels, samples, ..self }
+    }
+
+    /// Changes the sample rate by linear interpolation, which is plenty for speech.
+    pub(crate) fn resampled(self, sample_rate: u32) -> Self {
+        if sample_rate == self.sample_rate || sample_rate == 0 {
+            return self;
+        }
+
+        let channels = usize::from(self.channels);
+        let frames = self.samples.len() / channels;
+        if frames == 0 {
+            return Self { sample_rate, ..self };
+        }
+
+        let step = f64::from(self.sample_rate) / f64::from(sample_rate);
+        let resampled_frames = (frames as f64 / step).floor() as usize;
+        let mut samples = Vec::with_capacity(resampled_frames * channels);
+        for frame in 0..resampled_frames {
+            let position = frame as f64 * step;
+            let before = (position.floor() as usize).min(frames - 1);
+            let after = (before + 1).min(frames - 1);
+            let fraction = position - before as f64;
+
+            for channel in 0..channels {
+                let a = f64::from(self.samples[before * channels + channel]);
+                let b = f64::from(self.samples[after * channels + channel]);
+                samples.push((a + (b - a) * fraction).round() as i16);
+            }
+        }
+
+        Self { sample_rate, samples, ..self }
+    }
+
+    /// Scales the audio to the given RMS level (in dBFS), without letting the loudest sample clip.
+    pub(crate) fn normalize(&mut self, loudness: f32) {
+        if self.samples.is_empty() {
+            return;
+        }
+
+        let full_scale = f64::from(i16::MAX);
+        let sum_of_squares = self.samples.iter().map(|&sample| (f64::from(sample) / full_scale).powi(2)).sum::<f64>();
+        let rms = (sum_of_squares / self.samples.len() as f64).sqrt();
+        let peak = self.samples.iter().map(|&sample| i32::from(sample).unsigned_abs()).max().unwrap_or(0);
+        if rms == 0.0 || peak == 0 {
+            return; // Silence stays silent
+        }
+
+        let target = 10f64.powf(f64::from(loudness) / 20.0);
+        let gain = (target / rms).min(full_scale / f64::from(peak));
+        for sample in &mut self.samples {
+            *sample = (f64::from(*sample) * gain).round().clamp(f64::from(i16::MIN), full_scale) as i16;
+        }
+    }
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A PCM fmt chunk body for 16-bit audio.
+    fn pcm_format(channels: u16, sample_rate: u32) -> Vec<u8> {
+        let wav = Audio { sample_rate, channels, samples: vec![] }.to_wav();
+        parse(&wav).unwrap().format.to_vec()
+    }
+
+    /// A WAV file made of the given chunks, each with the size it declares and the body that follows;
+    /// odd-sized bodies are padded as they should be.
+    fn riff(chunks: &[(&[u8; 4], u32, &[u8])]) -> Vec<u8> {
+        let mut wav = b"RIFF\xFF\xFF\xFF\xFFWAVE".to_vec();
+        for (id, size, body) in chunks {
+            wav.extend_from_slice(*id);
+            wav.extend_from_slice(&size.to_le_bytes());
+            wav.extend_from_slice(body);
+            wav.resize(wav.len() + body.len() % 2, 0);
+        }
+        wav
+    }
+
+    fn samples(audio: &[u8]) -> Vec<i16> {
+        parse(audio).unwrap().audio().unwrap().samples
+    }
+
+    #[test]
+    fn parses_data_sizes_of_minus_one_and_past_the_end_as_the_rest_of_the_file() {
+        let format = pcm_format(1, 8000);
+        for size in [u32::MAX, 1000] {
+            let wav = riff(&[(b"fmt ", format.len() as u32, &format), (b"data", size, &[1, 0, 2, 0])]);
+            let parsed = parse(&wav).unwrap();
+            assert_eq!(parsed.format, format);
+            assert_eq!(parsed.data, [1, 0, 2, 0], "data size {}", size);
+        }
+    }
+
+    #[test]
+    fn skips_other_chunks_and_their_padding() {
+        let format = pcm_format(1, 8000);
+        let wav = riff(&[
+            (b"LIST", 4, b"INFO"),
+            (b"fmt ", format.len() as u32, &format),
+            (b"junk", 3, b"odd"),
+            (b"data", 4, &[1, 0, 2, 0]),
+        ]);
+        let parsed = parse(&wav).unwrap();
+        assert_eq!(parsed.format, format);
+        assert_eq!(parsed.data, [1, 0, 2, 0]);
+    }
+
+    #[test]
+    fn rejects_files_without_format_or_data() {
+        let format = pcm_format(1, 8000);
+        assert!(parse(b"RIFF\0\0\0\0AVI ").is_err());
+        assert!(parse(&riff(&[(b"data", 2, &[0, 0]), (b"fmt ", format.len() as u32, &format)])).is_err());
+        assert!(parse(&riff(&[(b"fmt ", format.len() as u32, &format)])).is_err());
+    }
+
+    #[test]
+    fn decodes_extensible_pcm() {
+        let mut format = pcm_format(2, 22050);
+        format[0..2].copy_from_slice(&FORMAT_EXTENSIBLE.to_le_bytes());
+        format.extend_from_slice(&22u16.to_le_bytes()); // The size of the extension
+        format.extend_from_slice(&16u16.to_le_bytes()); // Valid bits per sample
+        format.extend_from_slice(&3u32.to_le_bytes()); // Front left and right
+        format.extend_from_slice(&FORMAT_PCM.to_le_bytes()); // The rest of the GUID doesn't matter
+        format.resize(40, 0);
+
+        let wav = riff(&[(b"fmt ", format.len() as u32, &format), (b"data", 4, &[1, 0, 255, 255])]);
+        let audio = parse(&wav).unwrap().audio().unwrap();
+        assert_eq!(audio, Audio { sample_rate: 22050, channels: 2, samples: vec![1, -1] });
+    }
+
+    #[test]
+    fn converts_between_mono_and_stereo() {
+        let stereo = Audio { sample_rate: 8000, channels: 2, samples: vec![100, 300, -100, -301] };
+        assert_eq!(stereo.clone().with_channels(1).samples, [200, -200]);
+        assert_eq!(stereo.clone().with_channels(2), stereo);
+
+        let mono = Audio { sample_rate: 8000, channels: 1, samples: vec![5, -7] };
+        let spread = mono.with_channels(2);
+        assert_eq!((spread.channels, spread.samples), (2, vec![5, 5, -7, -7]));
+    }
+
+    #[test]
+    fn resamples_by_interpolating() {
+        let audio = Audio { sample_rate: 8000, channels: 1, samples: vec![0, 100, 200, 300] };
+
+        let up = audio.clone().resampled(16000);
+        assert_eq!((up.sample_rate, up.samples), (16000, vec![0, 50, 100, 150, 200, 250, 300, 300]));
+
+        let down = audio.clone().resampled(4000);
+        assert_eq!((down.sample_rate, down.samples), (4000, vec![0, 200]));
+
+        let stereo = Audio { sample_rate: 8000, channels: 2, samples: vec![0, 10, 100, 110] };
+        assert_eq!(stereo.resampled(16000).samples, [0, 10, 50, 60, 100, 110, 100, 110]);
+    }
+
+    #[test]
+    fn normalizes_loudness_without_clipping() {
+        let mut quiet = Audio { sample_rate: 8000, channels: 1, samples: vec![1000, -1000] };
+        quiet.normalize(-20.0);
+        assert_eq!(quiet.samples, [3277, -3277]);
+
+        // Reaching 0 dBFS would take a gain of about 4, which would clip the peak, so the peak limits it to 2
+        let mut peaky = Audio { sample_rate: 8000, channels: 1, samples: vec![16384, -16384, 0, 0, 0, 0, 0, 0] };
+        peaky.normalize(0.0);
+        assert_eq!(peaky.samples, [32767, -32767, 0, 0, 0, 0, 0, 0]);
+
+        let mut silence = Audio { sample_rate: 8000, channels: 1, samples: vec![0, 0] };
+        silence.normalize(-20.0);
+        assert_eq!(silence.samples, [0, 0]);
+    }
+
+    #[test]
+    fn joins_files_with_placeholder_sizes() {
+        let format = pcm_format(1, 8000);
+        let first = riff(&[(b"fmt ", format.len() as u32, &format), (b"data", u32::MAX, &[1, 0])]);
+        let second = riff(&[(b"LIST", 2, b"xx"), (b"fmt ", format.len() as u32, &format), (b"data", u32::MAX, &[2, 0])]);
+
+        let joined = join(&[first.clone(), second.clone()], &AudioOptions::default()).unwrap();
+        assert_eq!(u32_at(&joined, 4) as usize, joined.len() - CHUNK_HEADER_SIZE);
+        assert_eq!(samples(&joined), [1, 2]);
+
+        let options = AudioOptions { sample_rate: Some(16000), channels: Some(2), loudness: None };
+        let converted = join(&[first, second], &options).unwrap();
+        let audio = parse(&converted).unwrap().audio().unwrap();
+        assert_eq!((audio.sample_rate, audio.channels), (16000, 2));
+        assert_eq!(audio.samples, [1, 1, 1, 1, 2, 2, 2, 2]);
+    }
+}
@@ -1,53 +1,180 @@
-use crate::ai::{MessageReceiver, ServiceRequest, ServiceResponse};
+use crate::ai::{AiService, ClientRequest, ComparisonProfile, MessageReceiver, QueryOverrides, RecordedBody, ServiceRequest, ServiceResponse};
+use crate::cache::{CacheLimits, MessageCache};
 use std::collections::HashMap;
-use std::error::Error;
+use std::convert::Infallible;
+use std::fmt::Display;
+use std::future::Future;
+use std::io::Cursor;
 use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
 use base64::Engine;
 use base64::prelude::BASE64_STANDARD;
 use bytes::Bytes;
+use image::{ImageError, ImageFormat};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use tokio::sync::Mutex;
-use warp::http::{HeaderMap, Response};
-use warp::{Filter, Rejection};
+use tokio::sync::{broadcast, Mutex};
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::{Stream, StreamExt};
+use warp::http::{HeaderMap, Response, StatusCode};
+use warp::sse::Event;
+use warp::{Filter, Rejection, Reply};
 use crate::ai::OpenAiMessage::CreateSpeechResponse;
 use crate::ai::ServiceMessage;
+use crate::auth::ConsoleAuth;
+use crate::error::ServiceFailure;
+use crate::export::{ExportFormat, ExportItem};
+use crate::feedback::{Correction, Feedback};
+use crate::search::{CallSummary, RequestQuery};
+use crate::session::SessionKey;
+use crate::stats::PipelineStats;
+use crate::store::{image_mime_type, HistoryStore};
 use crate::types::{RequestBody, RequestParams, ResponseBody};
 
 #[derive(Clone)]
 pub(crate) struct WebConsoleService {
-    cache: Arc<Mutex<MessageCache>>
+    ai: Arc<AiService>,
+    cache: Arc<Mutex<MessageCache>>,
+    /// What `/api/request` filters on, for every call in memory or on disk.
+    /// Kept up to date as calls change; calls that are only on disk are added the first time they're searched.
+    index: Arc<Mutex<HashMap<u64, CallSummary>>>,
+    /// Held from serializing a call until it's saved,
+    /// so that an older copy of a call can't be saved after a newer one.
+    saving: Arc<Mutex<()>>,
+    store: Option<Arc<HistoryStore>>,
+    stats: Arc<PipelineStats>,
+    auth: Arc<ConsoleAuth>,
+    /// When this process started, in milliseconds since the Unix epoch.
+    /// Identifies which run of groan recorded each call.
+    session: u64,
+    events: broadcast::Sender<ConsoleEvent>,
+}
+
+/// A change to a service call, as pushed to the console's live listeners.
+#[derive(Clone)]
+struct ConsoleEvent {
+    id: u64,
+    kind: &'static str,
+    /// The whole call if it's new, or else a `CallUpdate`;
+    /// already serialized so that it's only done once for all listeners.
+    data: Arc<str>,
+}
+
+/// What one message changed about a call, as pushed to the console's live listeners.
+/// They merge it into their copy of the call, so that the whole call isn't sent again for every message.
+#[derive(Default, Serialize)]
+struct CallUpdate<'a> {
+    /// Goes at the end of the call's `openai_messages`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    openai_message: Option<&'a crate::ai::OpenAiMessage>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    replay: Option<&'a Replay>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    corrected_from: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    client_response: Option<&'a ServiceResponse>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<&'a ServiceFailure>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response_text: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    artifacts: Option<&'a Artifacts>,
+    /// `Some(None)` if the feedback was cleared.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    feedback: Option<Option<&'a Feedback>>,
 }
 
 #[derive(Default, Debug, Serialize, Deserialize)]
 pub(crate) struct RequestIds {
     pub(crate) ids: Vec<u64>,
+    /// How many calls matched, across all pages.
+    pub(crate) total: usize,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct ComparisonRequest {
+    pub(crate) profiles: Vec<ComparisonProfile>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub(crate) struct ExportQuery {
+    #[serde(default)]
+    pub(crate) format: ExportFormat,
 }
 
+/// The longest side of a thumbnail in the console's list, in pixels.
+const THUMBNAIL_SIZE: u32 = 128;
+
+/// Each profile in a comparison costs a chat completion, so don't let one click spend too much.
+const MAX_COMPARISON_PROFILES: usize = 8;
+
 #[derive(Serialize)]
 pub(crate) struct ServiceCall {
+    /// When the request was received, in milliseconds since the Unix epoch.
+    pub(crate) timestamp: u64,
+    pub(crate) session: u64,
     pub(crate) client_request: ServiceRequest,
+    /// Set if this call was made from the console by replaying an earlier one.
+    pub(crate) replay: Option<Replay>,
     pub(crate) openai_messages: Vec<crate::ai::OpenAiMessage>,
     pub(crate) client_response: Option<ServiceResponse>,
+    pub(crate) error: Option<ServiceFailure>,
+    /// The text the client was sent or that was spoken to it, whichever backend it came from.
+    pub(crate) response_text: Option<String>,
+    pub(crate) artifacts: Artifacts,
+    /// Set if the output was taken from a correction of the call with this ID instead of asking the model.
+    pub(crate) corrected_from: Option<u64>,
+    pub(crate) feedback: Option<Feedback>,
+}
+
+/// Where the console can fetch each of a call's outputs from, for the outputs that exist.
+#[derive(Debug, Default, Serialize)]
+pub(crate) struct Artifacts {
+    pub(crate) text: Option<String>,
+    pub(crate) sound: Option<String>,
+    /// The image that RetroArch draws over the game.
+    pub(crate) image: Option<String>,
+}
+
+impl Artifacts {
+    pub(crate) fn text_url(id: u64) -> String {
+        format!("/api/response/{}/text", id)
+    }
+
+    pub(crate) fn sound_url(id: u64) -> String {
+        format!("/api/response/{}/sound", id)
+    }
+
+    pub(crate) fn image_url(id: u64) -> String {
+        format!("/api/response/{}/image", id)
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct Replay {
+    pub(crate) of: u64,
+    pub(crate) overrides: QueryOverrides,
 }
 
 impl ServiceCall {
-    pub(crate) fn new(client_request: ServiceRequest) -> Self {
+    pub(crate) fn new(session: u64, client_request: ServiceRequest) -> Self {
         Self {
+            timestamp: unix_millis(),
+            session,
             client_request,
+            replay: None,
             openai_messages: vec![],
             client_response: None,
+            error: None,
+            response_text: None,
+            artifacts: Artifacts::default(),
+            corrected_from: None,
+            feedback: None,
         }
     }
 }
 
-#[derive(Default)]
-pub(crate) struct MessageCache {
-    service_calls: HashMap<u64, ServiceCall>,
-    request_images: HashMap<u64, Vec<u8>>,
-    response_sounds: HashMap<u64, Bytes>,
-}
-
 const HTML: &str = include_str!(concat!(env!("OUT_DIR"), "/index.html"));
 const JS: &str = include_str!(concat!(env!("OUT_DIR"), "/app.js"));
 const JS_MAP: &str = include_str!(concat!(env!("OUT_DIR"), "/app.js.map"));
@@ -55,58 +182,57 @@ const CSS: &str = include_str!(concat!(env!("OUT_DIR"), "/app.css"));
 const CSS_MAP: &str = include_str!(concat!(env!("OUT_DIR"), "/app.css.map"));
 
 impl WebConsoleService {
-    pub(crate) fn new() -> Self {
+    pub(crate) fn new(
+        ai: Arc<AiService>,
+        limits: CacheLimits,
+        store: Option<Arc<HistoryStore>>,
+        stats: Arc<PipelineStats>,
+        auth: ConsoleAuth,
+    ) -> Self {
         Self {
-            cache: Arc::new(Mutex::new(MessageCache::default()))
+            ai,
+            cache: Arc::new(Mutex::new(MessageCache::new(limits))),
+            index: Arc::new(Mutex::new(HashMap::new())),
+            saving: Arc::new(Mutex::new(())),
+            store,
+            stats,
+            auth: Arc::new(auth),
+            session: unix_millis(),
+            events: broadcast::channel(64).0,
         }
     }
 
     pub(crate) fn server_filter(self) -> impl Filter<Extract = (impl warp::Reply,), Error = Rejection> + Clone {
+        let auth = self.auth.clone().filter();
+
         let index_html = warp::get()
             .and(warp::path::end())
             .map(move || warp::reply::html(HTML));
 
         let style_css = warp::get().and(warp::path("app.css")).map(|| {
-            Response::builder()
-                .header("Content-Type", "text/css; charset=utf-8")
-                .body(CSS)
-                .unwrap()
+            warp::reply::with_header(CSS, "Content-Type", "text/css; charset=utf-8")
         });
 
         let style_css_map = warp::get().and(warp::path("app.css.map")).map(|| {
-            Response::builder()
-                .header("Content-Type", "application/json; charset=utf-8")
-                .body(CSS_MAP)
-                .unwrap()
+            warp::reply::with_header(CSS_MAP, "Content-Type", "application/json; charset=utf-8")
         });
 
         let index_js = warp::get().and(warp::path("app.js")).map(|| {
-            Response::builder()
-                .header("Content-Type", "text/javascript; charset=utf-8")
-                .body(JS)
-                .unwrap()
+            warp::reply::with_header(JS, "Content-Type", "text/javascript; charset=utf-8")
         });
 
         let index_js_map = warp::get().and(warp::path("app.js.map")).map(|| {
-            Response::builder()
-                .header("Content-Type", "application/json; charset=utf-8")
-                .body(JS_MAP)
-                .unwrap()
+            warp::reply::with_header(JS_MAP, "Content-Type", "application/json; charset=utf-8")
         });
 
         let me = self.clone();
         let requests = warp::path!("api" / "request")
             .and(warp::get())
-            .then(move || {
+            .and(warp::query::<RequestQuery>())
+            .then(move |query: RequestQuery| {
                 let me = me.clone();
                 async move {
-                    let cache = me.cache.lock().await;
-                    let requests = RequestIds { ids: cache.service_calls.keys().cloned().collect::<Vec<_>>() };
-                    
-                    Response::builder()
-                        .header("Content-Type", "application/json")
-                        .body(serde_json::to_string(&requests).unwrap())
-                        .unwrap()
+                    warp::reply::json(&me.search(&query).await)
                 }
             });
 
@@ -116,17 +242,98 @@ impl WebConsoleService {
             .and_then(move |id: u64| {
                 let me = me.clone();
                 async move {
-                    if let Some(call) = me.cache.lock().await.service_calls.get(&id) {
-                        let response = Response::builder()
-                            .header("Content-Type", "application/json")
-                            .body(serde_json::to_string(call).unwrap())
-                            .unwrap();
+                    let call = me.call_json(id).await.ok_or_else(warp::reject::not_found)?;
+                    Ok::<_, Rejection>(warp::reply::with_header(call, "Content-Type", "application/json"))
+                }
+            });
+
+        let me = self.clone();
+        let replay = warp::path!("api" / "request" / u64 / "replay")
+            .and(warp::post())
+            .and(warp::body::bytes())
+            .and_then(move |id: u64, body: Bytes| {
+                let me = me.clone();
+                async move {
+                    // An empty body means "replay as-is"
+                    let overrides = if body.is_empty() {
+                        QueryOverrides::default()
+                    } else {
+                        match serde_json::from_slice::<QueryOverrides>(&body) {
+                            Ok(overrides) => overrides,
+                            Err(e) => {
+                                let error = serde_json::json!({ "error": format!("Malformed overrides: {}", e) });
+                                return Ok(warp::reply::with_status(warp::reply::json(&error), StatusCode::BAD_REQUEST));
+                            }
+                        }
+                    };
+
+                    let (params, body) = me.recorded_request(id).await.ok_or_else(warp::reject::not_found)?;
+                    let (new_id, response, status) = me.ai.clone().replay(id, params, body, overrides).await;
+                    let reply = serde_json::json!({ "id": new_id, "response": response });
 
-                        return Ok(response);
+                    Ok::<_, Rejection>(warp::reply::with_status(warp::reply::json(&reply), status))
+                }
+            });
+
+        let me = self.clone();
+        let compare = warp::path!("api" / "request" / u64 / "compare")
+            .and(warp::post())
+            .and(warp::body::json::<ComparisonRequest>())
+            .and_then(move |id: u64, comparison: ComparisonRequest| {
+                let me = me.clone();
+                async move {
+                    if comparison.profiles.is_empty() || comparison.profiles.len() > MAX_COMPARISON_PROFILES {
+                        let error = serde_json::json!({ "error": format!("Give between 1 and {} profiles", MAX_COMPARISON_PROFILES) });
+                        return Ok(warp::reply::with_status(warp::reply::json(&error), StatusCode::BAD_REQUEST));
                     }
-                    else {
-                        Err(warp::reject::not_found())
+
+                    let image = me.request_image(id).await.ok_or_else(warp::reject::not_found)?;
+                    let results = me.ai.clone().compare(BASE64_STANDARD.encode(&image), comparison.profiles).await;
+
+                    Ok::<_, Rejection>(warp::reply::with_status(warp::reply::json(&results), StatusCode::OK))
+                }
+            });
+
+        let me = self.clone();
+        let feedback = warp::path!("api" / "request" / u64 / "feedback")
+            .and(warp::get())
+            .and_then(move |id: u64| {
+                let me = me.clone();
+                async move {
+                    let call = me.call_value(id).await.ok_or_else(warp::reject::not_found)?;
+                    let feedback = call.get("feedback").cloned().unwrap_or(Value::Null);
+                    Ok::<_, Rejection>(warp::reply::json(&feedback))
+                }
+            });
+
+        let me = self.clone();
+        let set_feedback = warp::path!("api" / "request" / u64 / "feedback")
+            .and(warp::put())
+            .and(warp::body::json::<Feedback>())
+            .and_then(move |id: u64, mut feedback: Feedback| {
+                let me = me.clone();
+                async move {
+                    // Clearing the text box means there's no correction
+                    feedback.correction = feedback.correction.filter(|correction| !correction.trim().is_empty());
+                    if !me.set_feedback(id, Some(feedback.clone())).await {
+                        return Err(warp::reject::not_found());
+                    }
+
+                    Ok::<_, Rejection>(warp::reply::json(&feedback))
+                }
+            });
+
+        let me = self.clone();
+        let clear_feedback = warp::path!("api" / "request" / u64 / "feedback")
+            .and(warp::delete())
+            .and_then(move |id: u64| {
+                let me = me.clone();
+                async move {
+                    if !me.set_feedback(id, None).await {
+                        return Err(warp::reject::not_found());
                     }
+
+                    Ok::<_, Rejection>(StatusCode::NO_CONTENT)
                 }
             });
 
@@ -136,31 +343,117 @@ impl WebConsoleService {
             .and_then(move |id: u64| {
                 let me = me.clone();
                 async move {
-                    let image = me.cache.lock().await.request_images.get(&id).ok_or_else(warp::reject::not_found)?.clone();
-                    let response = Response::builder()
-                        .header("Content-Type", "image/png")
-                        .body(image)
-                        .unwrap();
+                    let image = me.request_image(id).await.ok_or_else(warp::reject::not_found)?;
+                    let content_type = image_mime_type(&image);
+                    Ok::<_, Rejection>(warp::reply::with_header(image, "Content-Type", content_type))
+                }
+            });
 
-                    Ok::<_, Rejection>(response)
+        let me = self.clone();
+        let thumbnail = warp::path!("api" / "request" / u64 / "thumbnail")
+            .and(warp::get())
+            .and_then(move |id: u64| {
+                let me = me.clone();
+                async move {
+                    let thumbnail = me.thumbnail(id).await.ok_or_else(warp::reject::not_found)?;
+                    Ok::<_, Rejection>(warp::reply::with_header(Response::new(thumbnail), "Content-Type", "image/png"))
                 }
             });
 
+        let me = self.clone();
+        let response_image = warp::path!("api" / "response" / u64 / "image")
+            .and(warp::get())
+            .and_then(move |id: u64| {
+                let me = me.clone();
+                async move {
+                    let image = me.response_image(id).await.ok_or_else(warp::reject::not_found)?;
+                    let content_type = image_mime_type(&image);
+                    Ok::<_, Rejection>(warp::reply::with_header(Response::new(image), "Content-Type", content_type))
+                }
+            });
+
+        let me = self.clone();
+        let response_text = warp::path!("api" / "response" / u64 / "text")
+            .and(warp::get())
+            .and_then(move |id: u64| {
+                let me = me.clone();
+                async move {
+                    let call = me.call_value(id).await.ok_or_else(warp::reject::not_found)?;
+                    let text = call.get("response_text").and_then(Value::as_str).ok_or_else(warp::reject::not_found)?;
+                    Ok::<_, Rejection>(warp::reply::with_header(text.to_string(), "Content-Type", "text/plain; charset=utf-8"))
+                }
+            });
+
+        let me = self.clone();
         let sound = warp::path!("api" / "response" / u64 / "sound")
             .and(warp::get())
             .and_then(move |id: u64| {
-                let me = self.clone();
+                let me = me.clone();
+                async move {
+                    let sound = me.response_sound(id).await.ok_or_else(warp::reject::not_found)?;
+                    Ok::<_, Rejection>(warp::reply::with_header(Response::new(sound), "Content-Type", "audio/wav"))
+                }
+            });
+
+        let me = self.clone();
+        let export = warp::path!("api" / "export")
+            .and(warp::get())
+            .and(warp::query::<ExportQuery>())
+            .then(move |query: ExportQuery| {
+                let me = me.clone();
+                async move {
+                    let items = me.export_items().await;
+                    let format = query.format;
+                    let exported = tokio::task::spawn_blocking(move || crate::export::export(&items, format)).await;
+
+                    match exported {
+                        Ok(Ok(dataset)) => {
+                            let disposition = format!("attachment; filename=\"{}\"", format.file_name());
+                            let reply = warp::reply::with_header(dataset, "Content-Type", format.content_type());
+                            warp::reply::with_header(reply, "Content-Disposition", disposition).into_response()
+                        }
+                        Ok(Err(e)) => {
+                            log::error!(target: "groan", "Couldn't export history: {}", e);
+                            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+                        }
+                        Err(e) => {
+                            log::error!(target: "groan", "Export task failed: {}", e);
+                            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+                        }
+                    }
+                }
+            });
+
+        let me = self.clone();
+        let events = warp::path!("api" / "events")
+            .and(warp::get())
+            .map(move || warp::sse::reply(warp::sse::keep_alive().stream(me.event_stream())));
+
+        let me = self.clone();
+        let sessions = warp::path!("api" / "session")
+            .and(warp::get())
+            .map(move || warp::reply::json(&me.ai.sessions().list()));
+
+        let me = self.clone();
+        let reset_memory = warp::path!("api" / "session" / "memory")
+            .and(warp::delete())
+            .and(warp::query::<SessionKey>())
+            .and_then(move |key: SessionKey| {
+                let me = me.clone();
                 async move {
-                    let sound = me.cache.lock().await.response_sounds.get(&id).ok_or_else(warp::reject::not_found)?.clone();
-                    let response = Response::builder()
-                        .header("Content-Type", "audio/wav")
-                        .body(sound)
-                        .unwrap();
+                    if !me.ai.sessions().reset_memory(&key) {
+                        return Err(warp::reject::not_found());
+                    }
 
-                    Ok::<_, Rejection>(response)
+                    log::info!(target: "groan", "Reset the memory of {:?}", key);
+                    Ok::<_, Rejection>(StatusCode::NO_CONTENT)
                 }
             });
 
+        let stats = warp::path!("api" / "stats")
+            .and(warp::get())
+            .map(move || warp::reply::json(&*self.stats));
+
         let static_files = index_html
             .or(index_js)
             .or(style_css)
@@ -169,67 +462,588 @@ impl WebConsoleService {
 
         let api = requests
             .or(request)
+            .or(replay)
+            .or(compare)
+            .or(feedback)
+            .or(set_feedback)
+            .or(clear_feedback)
             .or(image)
-            .or(sound);
+            .or(thumbnail)
+            .or(response_image)
+            .or(response_text)
+            .or(sound)
+            .or(export)
+            .or(events)
+            .or(sessions)
+            .or(reset_memory)
+            .or(stats);
 
         warp::any()
+            .and(auth)
             .and(static_files.or(api))
+            .recover(ConsoleAuth::handle_rejection)
             .with(warp::trace::named("groan"))
     }
 
-    async fn handle_client_request(&mut self, id: u64, headers: HeaderMap, params: String, body: Bytes) -> Result<(), Box<dyn Error>> {
-        let body = serde_json::from_slice::<Value>(body.iter().as_slice())?;
-        let image = body.get("image").and_then(|i| i.as_str()).ok_or("No image in body")?;
-        let image = BASE64_STANDARD.decode(image.as_bytes())?;
-        let headers = HashMap::from_iter(headers.iter().map(|h| (h.0.to_string(), h.1.to_str().unwrap().to_string())));
+    async fn handle_client_request(&mut self, id: u64, request: ClientRequest) {
+        // The screenshot is decoded here, once, and only the decoded copy is kept;
+        // the recorded body just refers to it, and the AI service's copy is dropped once it's answered
+        let (body, image) = match request.body {
+            RecordedBody::Parsed(body) => {
+                let image = BASE64_STANDARD.decode(body.image.as_bytes()).map_err(|e| self.malformed(id, &e)).ok();
+                let body = serde_json::to_value(&*body).unwrap_or_else(|e| {
+                    self.malformed(id, &e);
+                    Value::Null
+                });
+                (body, image)
+            }
+            // Malformed requests are still worth recording; they're what the console is for
+            RecordedBody::Malformed(body) => {
+                self.malformed(id, &"the body isn't a valid request");
+                (Value::String(body), None)
+            }
+        };
+        let headers = header_strings(&request.headers);
+        let image_url = image.as_ref().map(|_| ServiceRequest::image_url(id));
+
+        let call = ServiceCall::new(self.session, ServiceRequest { headers, params: request.params, body, image: image_url });
+        let json = serde_json::to_string(&call);
+        let summary = summarize(id, &call);
+        if let (Some(store), Some(image)) = (&self.store, &image) {
+            if let Err(e) = store.save_image(id, image).await {
+                log::error!(target: "groan", "Couldn't save the screenshot for request {}: {}", id, e);
+            }
+        }
+
+        let _saving = self.saving.lock().await;
+        {
+            let mut cache = self.cache.lock().await;
+            if cache.contains(id) {
+                log::warn!(target: "groan", "Request {} was already recorded; ignoring the duplicate", id);
+                PipelineStats::count(&self.stats.malformed_messages);
+                return;
+            }
+
+            cache.insert(id, call, image);
+            self.forget_evicted(&mut cache).await;
+        }
+        if let Some(summary) = summary {
+            self.index.lock().await.insert(id, summary);
+        }
+
+        match json {
+            Ok(json) => {
+                self.save(id, &json).await;
+                self.notify(id, "client_request", json);
+            }
+            Err(e) => log::error!(target: "groan", "Couldn't serialize request {}: {}", id, e),
+        }
+    }
+
+    async fn handle_client_response(&mut self, id: u64, headers: HeaderMap, body: Bytes) {
+        let body = serde_json::from_slice::<Value>(body.iter().as_slice()).unwrap_or_else(|e| {
+            self.malformed(id, &e);
+            Value::String(String::from_utf8_lossy(&body).into_owned())
+        });
+        let headers = header_strings(&headers);
+
+        if !self.cache.lock().await.set_response(id, ServiceResponse {headers, body}) {
+            self.orphaned(id);
+            return;
+        }
+
+        self.updated(id, "client_response", |call| CallUpdate {
+            client_response: call.client_response.as_ref(),
+            ..CallUpdate::default()
+        })
+        .await;
+    }
+
+    /// IDs of every call in memory or on disk, in ascending order.
+    async fn ids(&self) -> Vec<u64> {
+        let mut ids = self.cache.lock().await.ids();
+        if let Some(store) = &self.store {
+            match store.ids().await {
+                Ok(stored) => ids.extend(stored),
+                Err(e) => log::error!(target: "groan", "Couldn't list stored requests: {}", e),
+            }
+        }
+
+        ids.sort_unstable();
+        ids.dedup();
+        ids
+    }
+
+    /// IDs of the calls that match the query, along with how many there are across all pages.
+    async fn search(&self, query: &RequestQuery) -> RequestIds {
+        let mut ids = self.ids().await;
+        if query.filters() {
+            self.index_stored(&ids).await;
+            let index = self.index.lock().await;
+            ids.retain(|id| index.get(id).is_some_and(|summary| query.matches(summary)));
+        }
+
+        let total = ids.len();
+        RequestIds { ids: query.page(ids), total }
+    }
+
+    /// Adds the calls that are only on disk to the search index, and forgets the calls that are gone altogether.
+    /// `ids` must be sorted.
+    async fn index_stored(&self, ids: &[u64]) {
+        let missing = {
+            let mut index = self.index.lock().await;
+            index.retain(|id, _| ids.binary_search(id).is_ok());
+            ids.iter().filter(|id| !index.contains_key(id)).copied().collect::<Vec<_>>()
+        };
+
+        for id in missing {
+            let Some(json) = self.load(id, "request", |store| store.load_call(id)).await else {
+                continue;
+            };
+
+            match serde_json::from_slice::<Value>(&json) {
+                // If the call changed while it was being loaded, it was already indexed as it is now
+                Ok(call) => {
+                    self.index.lock().await.entry(id).or_insert_with(|| CallSummary::new(&call));
+                }
+                Err(e) => self.malformed(id, &e),
+            }
+        }
+    }
+
+    /// Drops calls that the cache evicted from the search index, unless they're still on disk.
+    async fn forget_evicted(&self, cache: &mut MessageCache) {
+        let evicted = cache.take_evicted();
+        if self.store.is_none() && !evicted.is_empty() {
+            let mut index = self.index.lock().await;
+            for id in evicted {
+                index.remove(&id);
+            }
+        }
+    }
 
-        let mut cache = self.cache.lock().await;
-        assert!(!cache.service_calls.contains_key(&id));
-        cache.service_calls.insert(id, ServiceCall::new(ServiceRequest {headers, params, body}));
-        cache.request_images.insert(id, image);
+    /// The call with the given ID as JSON, from memory if possible and from disk if not.
+    async fn call_json(&self, id: u64) -> Option<Vec<u8>> {
+        let cached = self.cache.lock().await.call(id).map(serde_json::to_vec);
+        self.cached_or_stored(id, cached).await
+    }
 
-        Ok(())
+    /// Like `call_json`, but without counting as a use of the call in memory,
+    /// for going through every call without changing which are evicted first.
+    async fn peek_json(&self, id: u64) -> Option<Vec<u8>> {
+        let cached = self.cache.lock().await.peek(id).map(serde_json::to_vec);
+        self.cached_or_stored(id, cached).await
     }
 
-    async fn handle_client_response(&mut self, id: u64, headers: HeaderMap, body: Bytes) -> Result<(), Box<dyn Error>> {
-        let body = serde_json::from_slice::<Value>(body.iter().as_slice())?;
-        let headers = HashMap::from_iter(headers.iter().map(|h| (h.0.to_string(), h.1.to_str().unwrap().to_string())));
+    async fn cached_or_stored(&self, id: u64, cached: Option<serde_json::Result<Vec<u8>>>) -> Option<Vec<u8>> {
+        match cached {
+            Some(Ok(json)) => return Some(json),
+            Some(Err(e)) => log::error!(target: "groan", "Couldn't serialize request {}: {}", id, e),
+            None => {}
+        }
 
-        let mut cache = self.cache.lock().await;
-        assert!(cache.service_calls.contains_key(&id));
-        let call = cache.service_calls.get_mut(&id).unwrap();
-        call.client_response = Some(ServiceResponse {headers, body});
+        self.load(id, "request", |store| store.load_call(id)).await
+    }
 
-        Ok(())
+    /// The call with the given ID as a JSON value, for when only some of it is needed.
+    async fn call_value(&self, id: u64) -> Option<Value> {
+        serde_json::from_slice(&self.call_json(id).await?).ok()
+    }
+
+    /// Every call in memory or on disk, along with its artifacts.
+    async fn export_items(&self) -> Vec<ExportItem> {
+        let mut items = vec![];
+        for id in self.ids().await {
+            let Some(call) = self.peek_json(id).await.and_then(|json| serde_json::from_slice(&json).ok()) else {
+                continue;
+            };
+
+            // Peeked rather than fetched as usual, so that exporting doesn't change which calls are evicted first
+            let (image, sound) = {
+                let mut cache = self.cache.lock().await;
+                (cache.peek_image(id).cloned(), cache.peek_sound(id).map(|sound| sound.to_vec()))
+            };
+            let image = match image {
+                Some(image) => Some(image),
+                None => self.load(id, "screenshot", |store| store.load_image(id)).await,
+            };
+            let sound = match sound {
+                Some(sound) => Some(sound),
+                None => self.load(id, "sound", |store| store.load_sound(id)).await,
+            };
+
+            items.push(ExportItem { id, call, image, sound });
+        }
+
+        items
+    }
+
+    /// The query parameters and body of a recorded request, as RetroArch sent them.
+    /// The screenshot is put back into the body, since it's recorded separately.
+    async fn recorded_request(&self, id: u64) -> Option<(String, Value)> {
+        let call = self.call_value(id).await?;
+        let request = call.get("client_request")?;
+        let params = request.get("params")?.as_str()?.to_string();
+        let mut body = request.get("body")?.clone();
+
+        if let Some(body) = body.as_object_mut() {
+            if let Some(image) = self.request_image(id).await {
+                body.insert("image".to_string(), Value::String(BASE64_STANDARD.encode(image)));
+            }
+        }
+
+        Some((params, body))
+    }
+
+    /// Records a person's feedback on a call and hands any correction in it to the AI service.
+    /// Returns `false` if there's no such call.
+    async fn set_feedback(&self, id: u64, feedback: Option<Feedback>) -> bool {
+        let Some(mut call) = self.call_value(id).await else {
+            return false;
+        };
+        if let Some(object) = call.as_object_mut() {
+            object.insert("feedback".to_string(), serde_json::to_value(&feedback).unwrap_or(Value::Null));
+        }
+
+        if self.cache.lock().await.set_feedback(id, feedback.clone()) {
+            self.updated(id, "feedback", |call| CallUpdate { feedback: Some(call.feedback.as_ref()), ..CallUpdate::default() })
+                .await;
+        } else {
+            // Only on disk, so update the saved copy directly
+            self.index.lock().await.insert(id, CallSummary::new(&call));
+            let _saving = self.saving.lock().await;
+            match serde_json::to_string(&call) {
+                Ok(json) => self.save(id, &json).await,
+                Err(e) => log::error!(target: "groan", "Couldn't serialize request {}: {}", id, e),
+            }
+            let update = CallUpdate { feedback: Some(feedback.as_ref()), ..CallUpdate::default() };
+            match serde_json::to_string(&update) {
+                Ok(json) => self.notify(id, "feedback", json),
+                Err(e) => log::error!(target: "groan", "Couldn't serialize the feedback for request {}: {}", id, e),
+            }
+        }
+
+        match self.correction(id, &call).await {
+            Some(correction) => self.ai.set_correction(correction),
+            None => self.ai.remove_correction(id),
+        }
+        true
+    }
+
+    /// The correction in a call's feedback, if it has one and the screenshot it corrects was recorded intact.
+    async fn correction(&self, id: u64, call: &Value) -> Option<Correction> {
+        let text = call.pointer("/feedback/correction")?.as_str()?.trim();
+        if text.is_empty() {
+            return None;
+        }
+
+        let image = self.request_image(id).await?;
+
+        Some(Correction::new(id, &image, text.to_string()))
+    }
+
+    /// Hands every correction in the history store to the AI service, so that they outlive restarts.
+    pub(crate) async fn load_corrections(&self) {
+        let Some(store) = &self.store else {
+            return;
+        };
+
+        let ids = match store.ids().await {
+            Ok(ids) => ids,
+            Err(e) => {
+                log::error!(target: "groan", "Couldn't list stored requests: {}", e);
+                return;
+            }
+        };
+
+        let mut loaded = 0;
+        for id in ids {
+            let Some(call) = self.call_value(id).await else {
+                continue;
+            };
+
+            if let Some(correction) = self.correction(id, &call).await {
+                self.ai.set_correction(correction);
+                loaded += 1;
+            }
+        }
+
+        log::info!(target: "groan", "Loaded {} corrections from history", loaded);
+    }
+
+    async fn request_image(&self, id: u64) -> Option<Vec<u8>> {
+        let cached = self.cache.lock().await.image(id).cloned();
+        match cached {
+            Some(image) => Some(image),
+            None => self.load(id, "screenshot", |store| store.load_image(id)).await,
+        }
+    }
+
+    async fn response_image(&self, id: u64) -> Option<Bytes> {
+        let cached = self.cache.lock().await.response_image(id).cloned();
+        match cached {
+            Some(image) => Some(image),
+            None => self.load(id, "response image", |store| store.load_response_image(id)).await.map(Bytes::from),
+        }
+    }
+
+    /// A small PNG of the screenshot for the console's list.
+    /// It's only made the first time it's asked for, then kept alongside the call.
+    async fn thumbnail(&self, id: u64) -> Option<Bytes> {
+        let cached = self.cache.lock().await.thumbnail(id).cloned();
+        if cached.is_some() {
+            return cached;
+        }
+
+        if let Some(stored) = self.load(id, "thumbnail", |store| store.load_thumbnail(id)).await {
+            let stored = Bytes::from(stored);
+            self.cache.lock().await.set_thumbnail(id, stored.clone());
+            return Some(stored);
+        }
+
+        let image = self.request_image(id).await?;
+        let thumbnail = match tokio::task::spawn_blocking(move || make_thumbnail(&image)).await {
+            Ok(Ok(thumbnail)) => Bytes::from(thumbnail),
+            Ok(Err(e)) => {
+                log::warn!(target: "groan", "Couldn't make a thumbnail for request {}: {}", id, e);
+                return None;
+            }
+            Err(e) => {
+                log::error!(target: "groan", "Thumbnail task failed: {}", e);
+                return None;
+            }
+        };
+
+        self.cache.lock().await.set_thumbnail(id, thumbnail.clone());
+        if let Some(store) = &self.store {
+            if let Err(e) = store.save_thumbnail(id, &thumbnail).await {
+                log::error!(target: "groan", "Couldn't save the thumbnail for request {}: {}", id, e);
+            }
+        }
+
+        Some(thumbnail)
+    }
+
+    async fn response_sound(&self, id: u64) -> Option<Bytes> {
+        let cached = self.cache.lock().await.sound(id).cloned();
+        match cached {
+            Some(sound) => Some(sound),
+            None => self.load(id, "sound", |store| store.load_sound(id)).await.map(Bytes::from),
+        }
+    }
+
+    async fn load<'a, F, Fut>(&'a self, id: u64, what: &str, load: F) -> Option<Vec<u8>>
+    where
+        F: FnOnce(&'a HistoryStore) -> Fut,
+        Fut: Future<Output = std::io::Result<Option<Vec<u8>>>>,
+    {
+        match load(self.store.as_deref()?).await {
+            Ok(contents) => contents,
+            Err(e) => {
+                log::error!(target: "groan", "Couldn't load the {} for request {}: {}", what, id, e);
+                None
+            }
+        }
+    }
+
+    /// Writes the call through to disk, updates its entry in the search index,
+    /// and pushes what changed about it to the console's live listeners.
+    async fn updated<F>(&self, id: u64, kind: &'static str, change: F)
+    where
+        F: FnOnce(&ServiceCall) -> CallUpdate<'_>,
+    {
+        let listening = self.events.receiver_count() > 0;
+        let _saving = self.saving.lock().await;
+        let (json, summary, update) = {
+            let mut cache = self.cache.lock().await;
+            self.forget_evicted(&mut cache).await;
+            let Some(call) = cache.call(id) else {
+                return; // Already evicted; what's on disk is the most we'll get
+            };
+
+            let json = self.store.is_some().then(|| serde_json::to_string(call));
+            let summary = summarize(id, call);
+            let update = listening.then(|| serde_json::to_string(&change(call)));
+            (json, summary, update)
+        };
+
+        if let Some(summary) = summary {
+            self.index.lock().await.insert(id, summary);
+        }
+
+        match json {
+            Some(Ok(json)) => self.save(id, &json).await,
+            Some(Err(e)) => log::error!(target: "groan", "Couldn't serialize request {}: {}", id, e),
+            None => {}
+        }
+        match update {
+            Some(Ok(update)) => self.notify(id, kind, update),
+            Some(Err(e)) => log::error!(target: "groan", "Couldn't serialize the {} update for request {}: {}", kind, id, e),
+            None => {}
+        }
+    }
+
+    async fn save(&self, id: u64, json: &str) {
+        if let Some(store) = &self.store {
+            if let Err(e) = store.save_call(id, json.as_bytes()).await {
+                log::error!(target: "groan", "Couldn't save request {}: {}", id, e);
+            }
+        }
+    }
+
+    fn notify(&self, id: u64, kind: &'static str, data: String) {
+        // Sending only fails if nobody's listening, which is fine
+        let _ = self.events.send(ConsoleEvent { id, kind, data: data.into() });
+    }
+
+    /// A stream of server-sent events, one for each message `poll_task` receives.
+    /// Each event is named after the kind of message, has the call's ID as its ID,
+    /// and carries the whole call if it's new, or a `CallUpdate` with what the message changed about it.
+    fn event_stream(&self) -> impl Stream<Item = Result<Event, Infallible>> {
+        BroadcastStream::new(self.events.subscribe()).map(|event| {
+            let event = match event {
+                Ok(event) => Event::default()
+                    .event(event.kind)
+                    .id(event.id.to_string())
+                    .data(&*event.data),
+                // The listener fell behind and missed some events, so it should re-fetch everything
+                Err(BroadcastStreamRecvError::Lagged(missed)) => Event::default()
+                    .event("lagged")
+                    .data(missed.to_string()),
+            };
+
+            Ok(event)
+        })
+    }
+
+    fn orphaned(&self, id: u64) {
+        let orphaned = PipelineStats::count(&self.stats.orphaned_messages);
+        log::warn!(target: "groan", "Received a message for unknown request {} ({} orphaned so far)", id, orphaned);
+    }
+
+    fn malformed(&self, id: u64, error: &dyn Display) {
+        let malformed = PipelineStats::count(&self.stats.malformed_messages);
+        log::warn!(target: "groan", "Couldn't decode part of request {} ({} malformed so far): {}", id, malformed, error);
     }
 
     pub(crate) async fn poll_task(&mut self, mut receiver: MessageReceiver) {
         while let Some((id, message)) = receiver.recv().await {
             match message {
-                ServiceMessage::ClientRequest(headers, params, body) => {
-                    if let Err(e) = self.handle_client_request(id, headers, params, body).await {
-                        log::error!("Error handling client request: {}", e);
+                ServiceMessage::ClientRequest(request) => {
+                    self.handle_client_request(id, request).await;
+                }
+                ServiceMessage::OpenAiMessage(message) => {
+                    let sound = match &message {
+                        CreateSpeechResponse(sound) => Some(sound.clone()),
+                        _ => None,
+                    };
+
+                    if !self.cache.lock().await.push_message(id, message) {
+                        self.orphaned(id);
+                        continue;
+                    }
+
+                    if let (Some(store), Some(sound)) = (&self.store, sound) {
+                        if let Err(e) = store.save_sound(id, &sound).await {
+                            log::error!(target: "groan", "Couldn't save the sound for request {}: {}", id, e);
+                        }
                     }
+                    self.updated(id, "openai_message", |call| CallUpdate {
+                        openai_message: call.openai_messages.last(),
+                        artifacts: Some(&call.artifacts),
+                        ..CallUpdate::default()
+                    })
+                    .await;
                 }
-                ServiceMessage::OpenAiMessage(ref message @ CreateSpeechResponse(ref audio)) => {
-                    let mut cache = self.cache.lock().await;
-                    assert!(cache.service_calls.contains_key(&id));
-                    cache.response_sounds.insert(id, audio.clone());
-                    let call = cache.service_calls.get_mut(&id).unwrap();
-                    call.openai_messages.push(message.clone());
+                ServiceMessage::Replay(of, overrides) => {
+                    if !self.cache.lock().await.set_replay(id, Replay { of, overrides }) {
+                        self.orphaned(id);
+                        continue;
+                    }
+
+                    self.updated(id, "replay", |call| CallUpdate { replay: call.replay.as_ref(), ..CallUpdate::default() }).await;
                 }
-                ServiceMessage::OpenAiMessage(message) => {
-                    let mut cache = self.cache.lock().await;
-                    assert!(cache.service_calls.contains_key(&id));
-                    let call = cache.service_calls.get_mut(&id).unwrap();
-                    call.openai_messages.push(message);
+                ServiceMessage::CorrectionUsed(of) => {
+                    if !self.cache.lock().await.set_corrected_from(id, of) {
+                        self.orphaned(id);
+                        continue;
+                    }
+
+                    self.updated(id, "correction_used", |call| CallUpdate {
+                        corrected_from: call.corrected_from,
+                        ..CallUpdate::default()
+                    })
+                    .await;
                 }
                 ServiceMessage::ClientResponse(headers, body) => {
-                    if let Err(e) = self.handle_client_response(id, headers, body).await {
-                        log::error!("Error handling client response: {}", e);
+                    self.handle_client_response(id, headers, body).await;
+                }
+                ServiceMessage::ResponseImage(image) => {
+                    if !self.cache.lock().await.set_response_image(id, image.clone()) {
+                        self.orphaned(id);
+                        continue;
+                    }
+
+                    if let Some(store) = &self.store {
+                        if let Err(e) = store.save_response_image(id, &image).await {
+                            log::error!(target: "groan", "Couldn't save the response image for request {}: {}", id, e);
+                        }
                     }
+                    self.updated(id, "response_image", |call| CallUpdate { artifacts: Some(&call.artifacts), ..CallUpdate::default() })
+                        .await;
+                }
+                ServiceMessage::ResponseText(text) => {
+                    if !self.cache.lock().await.set_response_text(id, text) {
+                        self.orphaned(id);
+                        continue;
+                    }
+
+                    self.updated(id, "response_text", |call| CallUpdate {
+                        response_text: call.response_text.as_deref(),
+                        artifacts: Some(&call.artifacts),
+                        ..CallUpdate::default()
+                    })
+                    .await;
+                }
+                ServiceMessage::Error(failure) => {
+                    if !self.cache.lock().await.set_error(id, failure) {
+                        self.orphaned(id);
+                        continue;
+                    }
+
+                    self.updated(id, "error", |call| CallUpdate { error: call.error.as_ref(), ..CallUpdate::default() }).await;
                 }
             }
         }
     }
 }
+
+fn make_thumbnail(image: &[u8]) -> Result<Vec<u8>, ImageError> {
+    let thumbnail = image::load_from_memory(image)?.thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE);
+    let mut cursor = Cursor::new(Vec::new());
+    thumbnail.write_to(&mut cursor, ImageFormat::Png)?;
+    Ok(cursor.into_inner())
+}
+
+/// A call's entry in the search index.
+fn summarize(id: u64, call: &ServiceCall) -> Option<CallSummary> {
+    match serde_json::to_value(call) {
+        Ok(call) => Some(CallSummary::new(&call)),
+        Err(e) => {
+            log::error!(target: "groan", "Couldn't index request {}: {}", id, e);
+            None
+        }
+    }
+}
+
+fn unix_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |duration| duration.as_millis() as u64)
+}
+
+/// Header values aren't guaranteed to be UTF-8, so anything else is replaced rather than rejected.
+fn header_strings(headers: &HeaderMap) -> HashMap<String, String> {
+    headers
+        .iter()
+        .map(|(name, value)| (name.to_string(), String::from_utf8_lossy(value.as_bytes()).into_owned()))
+        .collect()
+}